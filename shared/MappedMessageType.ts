@@ -1,4 +1,5 @@
 import {MessageType} from '../src-tauri/bindings/MessageType';
+import {SignalEvent} from "./Protocol";
 
 type TypeNames = MessageType["type"];
 // @ts-ignore TODO: fix this
@@ -6,7 +7,7 @@ type DetailType<T extends TypeNames> = Extract<MessageType, { type: T }>["detail
 
 // Define the utility type for the intersection type
 type CustomEventMapMember<T extends TypeNames> = {
-    [K in T]: CustomEvent<DetailType<K>>
+    [K in T]: SignalEvent<DetailType<K>>
 };
 
 export type MessageEventMap = {
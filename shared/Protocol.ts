@@ -0,0 +1,69 @@
+import {MessageType} from "../src-tauri/bindings/MessageType";
+import {ClientKind} from "../src-tauri/bindings/ClientKind";
+import {ErrorCode} from "../src-tauri/bindings/ErrorCode";
+
+/**
+ * Must match PROTOCOL_VERSION in src-tauri/src/ws/protocol.rs
+ */
+export const PROTOCOL_VERSION = 1;
+
+/**
+ * Optional protocol features implemented by the clients, see CAPABILITIES in src-tauri/src/ws/protocol.rs
+ */
+export const CAPABILITIES = ["requestIds", "sessions"];
+
+/**
+ * A message as sent on the wire, requestId is set when the sender wants an ack or an error back
+ */
+export type Signal = MessageType & { requestId?: number };
+
+/**
+ * Event dispatched for each received message, carrying its request id to answer it
+ */
+export class SignalEvent<T> extends CustomEvent<T> {
+    public readonly requestId?: number;
+
+    constructor(type: string, detail: T, requestId?: number) {
+        super(type, {detail});
+        this.requestId = requestId;
+    }
+}
+
+/**
+ * Close code sent by the desktop app when it refuses the hello
+ */
+export const PROTOCOL_ERROR_CLOSE_CODE = 1002;
+
+/**
+ * First message to send once connected to the desktop app, discord clients running side by side send different client ids
+ */
+export function hello(client: ClientKind, version: string, clientId?: string, capabilities: string[] = CAPABILITIES): MessageType {
+    return {
+        type: "hello",
+        detail: {
+            protocolVersion: PROTOCOL_VERSION,
+            minProtocolVersion: PROTOCOL_VERSION,
+            client,
+            clientId,
+            version,
+            capabilities,
+        }
+    };
+}
+
+/**
+ * Acknowledges the received message, nothing is sent if it didn't ask for it
+ */
+export function ack(event: SignalEvent<unknown>): MessageType | undefined {
+    if (event.requestId === undefined) {
+        return undefined;
+    }
+    return {type: "ack", detail: {requestId: event.requestId}};
+}
+
+/**
+ * Reports that the received message couldn't be handled
+ */
+export function error(event: SignalEvent<unknown>, code: ErrorCode, message: string): MessageType {
+    return {type: "error", detail: {requestId: event.requestId, code, message}};
+}
@@ -7,4 +7,11 @@ export class Settings{
     public static getPort(): number {
         return BdApi.Data.load(DiscordSourcePlugin.name, "wsPort");
     }
+
+    /**
+     * Get the secret required by the desktop app to accept the connection, generated by the desktop app on its first start
+     */
+    public static getSecret(): string {
+        return BdApi.Data.load(DiscordSourcePlugin.name, "secret") ?? "";
+    }
 }
\ No newline at end of file
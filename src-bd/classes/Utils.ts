@@ -1,6 +1,16 @@
 import DiscordSourcePlugin from "../index";
 
 export class Utils {
+    /**
+     * Identifies this discord client to the desktop app, so clients running side by side (e.g. Stable and Canary)
+     * each get their own streams
+     */
+    static getClientId(): string {
+        const releaseChannel = (window as any).GLOBAL_ENV?.RELEASE_CHANNEL ?? "stable";
+        const userId = DiscordSourcePlugin.UserStore?.getCurrentUser()?.id;
+        return userId ? `${releaseChannel}-${userId}` : releaseChannel;
+    }
+
     /**
      * Waits for an element to be added to the DOM
      * @param selector The query selector of the element
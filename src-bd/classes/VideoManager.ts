@@ -6,13 +6,20 @@ import {ICEEvent} from "../../src-tauri/bindings/ICEEvent";
 import {AnswerOfferEvent} from "../../src-tauri/bindings/AnswerOfferEvent";
 import DiscordSourcePlugin from "../index";
 import {UpdateUserInfoEvent} from "../../src-tauri/bindings/UpdateUserInfoEvent";
+import {StreamKind} from "../../src-tauri/bindings/StreamKind";
+import {ErrorEvent} from "../../src-tauri/bindings/ErrorEvent";
+import {SignalEvent} from "../../shared/Protocol";
 
 interface DiscordStream {
     canvas?: HTMLCanvasElement;
-    peerConnection?: WebRTCStream;
+    /**
+     * One peer connection per target watching the stream, keyed by session id
+     */
+    sessions: Map<string, WebRTCStream>;
     mutationObserver?: MutationObserver;
     userId: string;
     nickname: string;
+    kind: StreamKind;
 }
 
 export class VideoManager {
@@ -25,8 +32,10 @@ export class VideoManager {
         this.ws = ws;
         this.ws.addEventListener("capture", (e) => this.onRequestCaptureVideoStream(e));
         this.ws.addEventListener("endCapture", (e) => this.onEndCaptureVideoStream(e));
+        this.ws.addEventListener("endSession", (e) => this.onEndSession(e));
         this.ws.addEventListener("answer", (e) => this.onAnswerEvent(e));
         this.ws.addEventListener("ice", (e) => this.onIceCandidateEvent(e));
+        this.ws.addEventListener("error", (e) => this.onErrorEvent(e));
 
         DiscordSourcePlugin.CallStore.addChangeListener(this.onCallStateChangeBinded);
 
@@ -51,13 +60,14 @@ export class VideoManager {
         let streamParticipants = DiscordSourcePlugin.CallStore.getStreamParticipants(currentChannelId);
         let videoParticipants = DiscordSourcePlugin.CallStore.getVideoParticipants(currentChannelId);
 
-        let participants = streamParticipants.concat(videoParticipants);
+        let participants = streamParticipants.map(participant => ({participant, kind: "screen" as StreamKind}))
+            .concat(videoParticipants.map(participant => ({participant, kind: "camera" as StreamKind})));
 
         const newStreams = [];
 
         const currentStreams = new Set(this.streams.keys());
 
-        for (const participant of participants) {
+        for (const {participant, kind} of participants) {
             if (this.streams.has(participant.streamId)) {
                 currentStreams.delete(participant.streamId);
                 if (participant.localVideoDisabled) {
@@ -77,8 +87,10 @@ export class VideoManager {
             }
 
             this.streams.set(participant.streamId, {
+                sessions: new Map(),
                 userId: participant.id,
                 nickname: participant.userNick,
+                kind,
             });
 
             newStreams.push({
@@ -88,6 +100,7 @@ export class VideoManager {
                     nickname: participant.userNick,
                     streamPreview: preview
                 },
+                kind,
             });
         }
 
@@ -155,7 +168,8 @@ export class VideoManager {
                 info: {
                     nickname: stream.nickname,
                     streamPreview: preview,
-                }
+                },
+                kind: stream.kind,
             });
         }
 
@@ -180,24 +194,74 @@ export class VideoManager {
         clearInterval(this.updateInfoInterval);
         DiscordSourcePlugin.CallStore.removeChangeListener(this.onCallStateChangeBinded);
         await this.ws.close();
-        this.streams.forEach(stream => stream.peerConnection?.close());
+        this.streams.forEach(stream => stream.sessions.forEach(session => session.close()));
+    }
+
+    /**
+     * Desktop apps predating sessions run a single peer connection per stream and don't send a session id
+     */
+    private static sessionKey(detail: { streamId?: string, sessionId?: string }): string {
+        return detail.sessionId ?? detail.streamId;
     }
 
-    private async onRequestCaptureVideoStream(event: CustomEvent<CaptureEvent>) {
+    private async onRequestCaptureVideoStream(event: SignalEvent<CaptureEvent>) {
         const video = this.streams.get(event.detail.streamId);
         if (!video) {
             Utils.error("Received capture request for unknown stream", event.detail.streamId, "while we have", this.streams.keys());
+            this.ws.fail(event, "streamNotFound", `Unknown stream ${event.detail.streamId}`);
             return
         }
 
-        Utils.log(`Received capture request for stream ${event.detail.streamId}!`)
+        const sessionKey = VideoManager.sessionKey(event.detail);
+        Utils.log(`Received capture request for stream ${event.detail.streamId}, session ${sessionKey}!`)
+
+        if (!video.canvas) {
+            this.startCapture(event.detail.streamId, video);
+        }
+
+        video.sessions.get(sessionKey)?.close();
+        // Each session gets its own track of the canvas, closing a peer connection stops only its track
+        const peerConnection = new WebRTCStream(video.canvas.captureStream(30));
+        video.sessions.set(sessionKey, peerConnection);
+
+        peerConnection.peerConnection.addEventListener("icecandidate", ({candidate}) => {
+            if (!candidate) {
+                return;
+            }
+            this.ws.sendEvent({
+                type: "ice", detail: {
+                    streamId: event.detail.streamId, sessionId: event.detail.sessionId, candidate: JSON.stringify(candidate.toJSON())
+                }
+            })
+        });
+
+        let offer;
+        try {
+            offer = await peerConnection.start();
+        } catch (e) {
+            Utils.error("Failed to create offer for stream", event.detail.streamId, e);
+            this.ws.fail(event, "negotiationFailed", `Failed to create an offer: ${e}`);
+            return;
+        }
+
+        this.ws.sendEvent({
+            type: "offer", detail: {
+                sdp: offer.sdp, streamId: event.detail.streamId, sessionId: event.detail.sessionId
+            }
+        })
+        this.ws.ack(event);
+    }
 
+    /**
+     * Starts drawing the stream on a hidden canvas, shared by all the sessions of the stream
+     */
+    private startCapture(streamId: string, video: DiscordStream) {
         video.canvas = document.createElement("canvas");
-        video.canvas.id = "discord-source-canvas-" + event.detail.streamId;
+        video.canvas.id = "discord-source-canvas-" + streamId;
         video.canvas.style.display = "none";
         document.body.append(video.canvas);
 
-        DiscordSourcePlugin.VoiceEngine.addVideoOutputSink(video.canvas.id, event.detail.streamId, (width, height) => {
+        DiscordSourcePlugin.VoiceEngine.addVideoOutputSink(video.canvas.id, streamId, (width, height) => {
             video.canvas.width = width;
             video.canvas.height = height;
         });
@@ -208,8 +272,8 @@ export class VideoManager {
                 if (mutation.type === "childList" && mutation.removedNodes.length > 0) {
                     mutation.removedNodes.forEach((node) => {
                         const element = node as HTMLElement;
-                        if (element.id === "media-engine-video-" + event.detail.streamId) {
-                            DiscordSourcePlugin.VoiceEngine.addVideoOutputSink(video.canvas.id, event.detail.streamId, (width, height) => {
+                        if (element.id === "media-engine-video-" + streamId) {
+                            DiscordSourcePlugin.VoiceEngine.addVideoOutputSink(video.canvas.id, streamId, (width, height) => {
                                 video.canvas.width = width;
                                 video.canvas.height = height;
                             });
@@ -220,65 +284,84 @@ export class VideoManager {
         });
 
         video.mutationObserver.observe(document.body, { childList: true, subtree: true });
-
-        video.peerConnection = new WebRTCStream(video.canvas.captureStream(30));
-
-        video.peerConnection.peerConnection.addEventListener("icecandidate", ({candidate}) => {
-            if (!candidate) {
-                return;
-            }
-            this.ws.sendEvent({
-                type: "ice", detail: {
-                    streamId: event.detail.streamId, candidate: JSON.stringify(candidate.toJSON())
-                }
-            })
-        });
-
-        const offer = await video.peerConnection.start();
-
-        this.ws.sendEvent({
-            type: "offer", detail: {
-                sdp: offer.sdp, streamId: event.detail.streamId
-            }
-        })
     }
 
-    private onAnswerEvent(event: CustomEvent<AnswerOfferEvent>) {
-        const stream = this.streams.get(event.detail.streamId);
-        if (!stream) {
-            Utils.error("Received answer for unknown stream", event.detail.streamId, "while we have", this.streams.keys());
+    private async onAnswerEvent(event: SignalEvent<AnswerOfferEvent>) {
+        const peerConnection = this.streams.get(event.detail.streamId)?.sessions.get(VideoManager.sessionKey(event.detail));
+        if (!peerConnection) {
+            Utils.error("Received answer for unknown session", VideoManager.sessionKey(event.detail), "of stream", event.detail.streamId);
+            this.ws.fail(event, "streamNotFound", `No capture running for stream ${event.detail.streamId}`);
             return;
         }
         Utils.log("Received answer");
-        stream.peerConnection.peerConnection.setRemoteDescription({
-            type: "answer", sdp: event.detail.sdp
-        });
+        try {
+            await peerConnection.peerConnection.setRemoteDescription({
+                type: "answer", sdp: event.detail.sdp
+            });
+            this.ws.ack(event);
+        } catch (e) {
+            Utils.error("Failed to set answer for stream", event.detail.streamId, e);
+            this.ws.fail(event, "negotiationFailed", `Invalid answer: ${e}`);
+        }
+    }
+
+    /**
+     * A target stopped watching the stream while others still do, only its peer connection is closed
+     */
+    private onEndSession(event: SignalEvent<CaptureEvent>) {
+        const sessions = this.streams.get(event.detail.streamId)?.sessions;
+        const sessionKey = VideoManager.sessionKey(event.detail);
+        const peerConnection = sessions?.get(sessionKey);
+        if (!peerConnection) {
+            Utils.error("Received end session request for unknown session", sessionKey, "of stream", event.detail.streamId);
+            this.ws.fail(event, "streamNotFound", `No capture running for stream ${event.detail.streamId}`);
+            return;
+        }
+        Utils.log(`Received end session request for stream ${event.detail.streamId}, session ${sessionKey}!`)
+        peerConnection.close();
+        sessions.delete(sessionKey);
+        this.ws.ack(event);
     }
 
-    private onEndCaptureVideoStream(event: CustomEvent<CaptureEvent>) {
+    private onEndCaptureVideoStream(event: SignalEvent<CaptureEvent>) {
         const stream = this.streams.get(event.detail.streamId);
         if (!stream) {
             Utils.error("Received end capture request for unknown stream", event.detail.streamId, "while we have", this.streams.keys());
+            this.ws.fail(event, "streamNotFound", `Unknown stream ${event.detail.streamId}`);
             return;
         }
         Utils.log(`Received end capture request for stream ${event.detail.streamId}!`)
+        stream.sessions.forEach(session => session.close());
+        stream.sessions.clear();
         stream.mutationObserver?.disconnect();
-        stream.peerConnection.close();
-        stream.peerConnection = undefined;
-        DiscordSourcePlugin.VoiceEngine.removeVideoOutputSink(stream.canvas.id, event.detail.streamId);
-        document.body.removeChild(stream.canvas);
-        stream.canvas.remove();
-        stream.canvas = undefined;
+        stream.mutationObserver = undefined;
+        if (stream.canvas) {
+            DiscordSourcePlugin.VoiceEngine.removeVideoOutputSink(stream.canvas.id, event.detail.streamId);
+            stream.canvas.remove();
+            stream.canvas = undefined;
+        }
+        this.ws.ack(event);
     }
 
-    private onIceCandidateEvent(event: CustomEvent<ICEEvent>) {
-        const stream = this.streams.get(event.detail.streamId);
-        if (!stream) {
-            Utils.error("Received ICE Candidate for unknown stream", event.detail.streamId, "while we have", this.streams.keys());
+    private async onIceCandidateEvent(event: SignalEvent<ICEEvent>) {
+        const peerConnection = this.streams.get(event.detail.streamId)?.sessions.get(VideoManager.sessionKey(event.detail));
+        if (!peerConnection) {
+            Utils.error("Received ICE Candidate for unknown session", VideoManager.sessionKey(event.detail), "of stream", event.detail.streamId);
+            this.ws.fail(event, "streamNotFound", `No capture running for stream ${event.detail.streamId}`);
             return;
         }
         Utils.log("Received ICE candidate");
-        stream.peerConnection.peerConnection.addIceCandidate(new RTCIceCandidate(JSON.parse(event.detail.candidate)));
+        try {
+            await peerConnection.peerConnection.addIceCandidate(new RTCIceCandidate(JSON.parse(event.detail.candidate)));
+            this.ws.ack(event);
+        } catch (e) {
+            Utils.error("Failed to add ICE candidate for stream", event.detail.streamId, e);
+            this.ws.fail(event, "negotiationFailed", `Invalid ICE candidate: ${e}`);
+        }
+    }
+
+    private onErrorEvent(event: SignalEvent<ErrorEvent>) {
+        Utils.error(`Discord Source couldn't handle a message (${event.detail.code}): ${event.detail.message}`);
     }
 
 }
\ No newline at end of file
@@ -1,7 +1,11 @@
 import {TypedEventTarget} from 'typescript-event-target';
 import {MessageType} from "../../src-tauri/bindings/MessageType";
+import {ErrorCode} from "../../src-tauri/bindings/ErrorCode";
 import {Utils} from "./Utils";
 import {MessageEventMap} from "../../shared/MappedMessageType";
+import {ack, error, hello, PROTOCOL_ERROR_CLOSE_CODE, Signal, SignalEvent} from "../../shared/Protocol";
+import plugin from "../plugin.json";
+import {Settings} from "./Settings";
 
 export class WS extends TypedEventTarget<MessageEventMap> {
     private ws: WebSocket;
@@ -19,7 +23,8 @@ export class WS extends TypedEventTarget<MessageEventMap> {
     public async connect(): Promise<boolean> {
         if (this.isClosed) return false;
 
-        this.ws = new WebSocket(`ws://localhost:${this.port}/discord`);
+        // Read again on every attempt, the desktop app may have generated the secret after the plugin started
+        this.ws = new WebSocket(`ws://localhost:${this.port}/discord?token=${encodeURIComponent(Settings.getSecret())}`);
 
         const connectionState = await new Promise(resolve => {
             if (this.ws.readyState === WebSocket.OPEN) {
@@ -35,10 +40,17 @@ export class WS extends TypedEventTarget<MessageEventMap> {
 
         if (connectionState) {
             this.ws.addEventListener("message", (e) => this.eventHandler(e));
+            this.sendEvent(hello("discord", plugin.version, Utils.getClientId()));
 
-            this.ws.addEventListener("close", () => {
+            this.ws.addEventListener("close", (e) => {
                 if (this.isClosed) return;
 
+                if (e.code === PROTOCOL_ERROR_CLOSE_CODE) {
+                    Utils.log(`Discord Source refused the connection: ${e.reason}, update the desktop app and the plugin`);
+                    this.isClosed = true;
+                    return;
+                }
+
                 Utils.log("Connection to Discord Source lost, retrying...");
                 this.connect();
             })
@@ -61,10 +73,27 @@ export class WS extends TypedEventTarget<MessageEventMap> {
         this.ws.send(JSON.stringify(event));
     }
 
+    /**
+     * Acknowledges a message handled successfully, if the desktop app asked for it
+     */
+    public ack(event: SignalEvent<unknown>) {
+        const message = ack(event);
+        if (message) {
+            this.sendEvent(message);
+        }
+    }
+
+    /**
+     * Tells the desktop app why a message couldn't be handled
+     */
+    public fail(event: SignalEvent<unknown>, code: ErrorCode, message: string) {
+        this.sendEvent(error(event, code, message));
+    }
+
     private eventHandler(event: MessageEvent<string>) {
         Utils.log(`Received event from the desktop app`, event.data)
-        let data: MessageType = JSON.parse(event.data);
+        let data: Signal = JSON.parse(event.data);
         // @ts-ignore TODO: Remove ts-ignore
-        this.dispatchTypedEvent(data.type, new CustomEvent(data.type, {detail: data.detail}) as any);
+        this.dispatchTypedEvent(data.type, new SignalEvent(data.type, data.detail, data.requestId) as any);
     }
 }
\ No newline at end of file
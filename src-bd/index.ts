@@ -4,12 +4,14 @@ import {Utils} from "./classes/Utils";
 import {VideoManager} from "./classes/VideoManager";
 import {CallStore} from "./types/CallStore";
 import {ChannelStore} from "./types/ChannelStore";
+import {UserStore} from "./types/UserStore";
 
 export default class DiscordSourcePlugin {
     static videoManager: VideoManager;
     public static VoiceEngine = BdApi.Webpack.getModule(BdApi.Webpack.Filters.byProps("getVoiceEngine")).getVoiceEngine() as VoiceEngine;
     public static CallStore = BdApi.Webpack.getModule(BdApi.Webpack.Filters.byProps("getVideoParticipants", "getStreamParticipants")) as CallStore;
     public static ChannelStore = BdApi.Webpack.getModule(BdApi.Webpack.Filters.byProps("getVoiceChannelId")) as ChannelStore;
+    public static UserStore = BdApi.Webpack.getModule(BdApi.Webpack.Filters.byProps("getCurrentUser", "getUser")) as UserStore;
 
     async start() {
         if (!Settings.getPort()) {
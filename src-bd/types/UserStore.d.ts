@@ -0,0 +1,3 @@
+export interface UserStore {
+    getCurrentUser(): { id: string } | undefined;
+}
\ No newline at end of file
@@ -6,6 +6,7 @@ use tracing::{error, info};
 
 use crate::DEFAULT_WS_PORT;
 use crate::ds_installer::kill_discord;
+use crate::ws::auth::generate_secret;
 
 const PLUGIN: &str = include_str!("../../dist-bd/DiscordSourcePlugin.plugin.js");
 
@@ -13,12 +14,16 @@ const PLUGIN: &str = include_str!("../../dist-bd/DiscordSourcePlugin.plugin.js")
 pub struct BdSettings {
     #[serde(rename = "wsPort")]
     pub ws_port: u16,
+    /// Per-install secret the plugin sends on the `/discord` handshake, generated when missing
+    #[serde(default)]
+    pub secret: String,
 }
 
 impl BdSettings {
     pub fn new(port: u16) -> Self {
         Self {
-            ws_port: port
+            ws_port: port,
+            secret: generate_secret(),
         }
     }
 
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use parking_lot::Mutex as PLMutex;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::error;
+use ts_rs::TS;
+
+use crate::ws::message::{RemoveStreamEvent, StreamKind};
+use crate::ws::scenes::SceneActivatedEvent;
+
+/// How many past events are kept to replay to SSE clients resuming with Last-Event-ID
+const HISTORY_SIZE: usize = 256;
+
+/// Stream and target lifecycle events, published to the desktop window and to the `/api/events` SSE feed
+#[derive(Serialize, Debug, TS, Clone)]
+#[ts(export)]
+#[serde(tag = "type", content = "detail")]
+pub enum ServerEvent {
+    #[serde(rename = "stream-removed")]
+    StreamRemoved(Vec<RemoveStreamEvent>),
+    #[serde(rename = "user-info-update")]
+    UserInfoUpdate(Vec<StreamInfoEvent>),
+    #[serde(rename = "web-added")]
+    WebAdded(String),
+    #[serde(rename = "web-removed")]
+    WebRemoved(String),
+    /// A discord client went away along with its streams, carries its client id
+    #[serde(rename = "discord-disconnected")]
+    DiscordDisconnected(String),
+    #[serde(rename = "target-linked")]
+    TargetLinked(TargetLinkEvent),
+    #[serde(rename = "target-unlinked")]
+    TargetUnlinked(TargetLinkEvent),
+    /// The stream of a linked target went away with its discord client, the target is linked back if it returns
+    /// within the reconnect grace period and unlinked otherwise
+    #[serde(rename = "target-pending")]
+    TargetPending(TargetLinkEvent),
+    #[serde(rename = "protocol-mismatch")]
+    ProtocolMismatch(ProtocolMismatchEvent),
+    /// Every capture of the scene was asked for, the targets renegotiate on their own from there
+    #[serde(rename = "scene-activated")]
+    SceneActivated(SceneActivatedEvent),
+}
+
+/// A stream added or updated by discord, the preview itself is fetched from `/api/streams/{streamId}/preview`
+#[derive(Serialize, Debug, TS, Clone)]
+#[ts(export)]
+pub struct StreamInfoEvent {
+    #[serde(rename = "streamId")]
+    pub stream_id: String,
+    #[serde(rename = "clientId")]
+    pub client_id: String,
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    pub nickname: String,
+    #[serde(rename = "previewEtag")]
+    pub preview_etag: Option<String>,
+    #[ts(optional)]
+    pub kind: Option<StreamKind>,
+}
+
+#[derive(Serialize, Debug, TS, Clone)]
+#[ts(export)]
+pub struct TargetLinkEvent {
+    pub target: String,
+    #[serde(rename = "streamId")]
+    #[ts(optional)]
+    pub stream_id: Option<String>,
+    /// Auto-link rule that made the link, missing for the links made by hand or by following a user
+    #[ts(optional)]
+    pub rule: Option<String>,
+}
+
+/// A peer speaking an older protocol (downgraded) or one that couldn't be agreed on (refused)
+#[derive(Serialize, Debug, TS, Clone)]
+#[ts(export)]
+pub struct ProtocolMismatchEvent {
+    /// `discord`, `discord:<client id>` for a plugin that sent one, or the target id
+    pub peer: String,
+    #[serde(rename = "clientVersion")]
+    #[ts(optional)]
+    pub client_version: Option<String>,
+    #[serde(rename = "protocolVersion")]
+    pub protocol_version: u32,
+    #[serde(rename = "serverProtocolVersion")]
+    pub server_protocol_version: u32,
+    pub refused: bool,
+    pub reason: String,
+}
+
+impl ServerEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerEvent::StreamRemoved(_) => "stream-removed",
+            ServerEvent::UserInfoUpdate(_) => "user-info-update",
+            ServerEvent::WebAdded(_) => "web-added",
+            ServerEvent::WebRemoved(_) => "web-removed",
+            ServerEvent::DiscordDisconnected(_) => "discord-disconnected",
+            ServerEvent::TargetLinked(_) => "target-linked",
+            ServerEvent::TargetUnlinked(_) => "target-unlinked",
+            ServerEvent::TargetPending(_) => "target-pending",
+            ServerEvent::ProtocolMismatch(_) => "protocol-mismatch",
+            ServerEvent::SceneActivated(_) => "scene-activated",
+        }
+    }
+
+    /// The event detail alone, as the desktop window receives it
+    pub fn payload(&self) -> serde_json::Value {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(mut event)) => event.remove("detail").unwrap_or_default(),
+            _ => serde_json::Value::Null,
+        }
+    }
+}
+
+pub struct Envelope {
+    pub id: u64,
+    pub event: ServerEvent,
+}
+
+struct History {
+    next_id: u64,
+    events: VecDeque<Arc<Envelope>>,
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<Envelope>>,
+    history: Arc<PLMutex<History>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(HISTORY_SIZE);
+
+        Self {
+            sender,
+            history: Arc::new(PLMutex::new(History {
+                next_id: 1,
+                events: VecDeque::with_capacity(HISTORY_SIZE),
+            })),
+        }
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        let mut history = self.history.lock();
+        let envelope = Arc::new(Envelope {
+            id: history.next_id,
+            event,
+        });
+        history.next_id += 1;
+
+        if history.events.len() == HISTORY_SIZE {
+            history.events.pop_front();
+        }
+        history.events.push_back(envelope.clone());
+
+        // Sent while holding the history lock so subscribers never see an event both replayed and live
+        let _ = self.sender.send(envelope);
+    }
+
+    /// Publishes the event and emits it to the desktop window under the same name
+    pub fn emit<R: tauri::Runtime>(&self, window: &tauri::Window<R>, event: ServerEvent) {
+        if let Err(e) = window.emit(event.name(), event.payload()) {
+            error!("Failed to emit {} to the window: {}", event.name(), e);
+        }
+        self.publish(event);
+    }
+
+    /// Returns the kept events newer than `last_event_id` followed by a receiver for the live ones
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> (Vec<Arc<Envelope>>, broadcast::Receiver<Arc<Envelope>>) {
+        let history = self.history.lock();
+        let replay = match last_event_id {
+            Some(last_event_id) => history.events.iter().filter(|envelope| envelope.id > last_event_id).cloned().collect(),
+            None => Vec::new(),
+        };
+
+        (replay, self.sender.subscribe())
+    }
+}
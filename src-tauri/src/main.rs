@@ -3,15 +3,13 @@ all(not(debug_assertions), target_os = "windows"),
 windows_subsystem = "windows"
 )]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use futures_util::future::join_all;
-use futures_util::SinkExt;
 use parking_lot::Mutex as PLMutex;
 use tauri::{CustomMenuItem, Manager, RunEvent, SystemTray, SystemTrayEvent, SystemTrayMenu};
 use tokio::sync::RwLock;
-use tokio_tungstenite::tungstenite::Message;
 use tracing::{error, info};
 use tracing_log::LogTracer;
 use tracing_subscriber::{filter, Layer};
@@ -19,16 +17,33 @@ use tracing_subscriber::layer::SubscriberExt;
 
 use crate::bd::{BdSettings, get_bd_path, install_plugin};
 use crate::ds_installer::configure_open_asar;
+use crate::events::{EventBus, ServerEvent};
 use crate::license::{check_license, open_ds_invite};
+use crate::net::NetworkConfig;
+use crate::store::SessionStore;
+use crate::targets::{target_url, validate_id, TargetConfig, TargetInfo};
+use crate::tls::TlsConfig;
+use crate::web::display::DisplayOptions;
 use crate::web::WebServer;
-use crate::ws::{DiscordConnection, DiscordStream, DiscordStreams, WebConnections, WebSocketServer};
-use crate::ws::message::{CaptureEvent, MessageType};
+use crate::ws::auth::{generate_secret, Auth, AuthConfig};
+use crate::ws::heartbeat::HeartbeatConfig;
+use crate::ws::origins::{OriginConfig, OriginPolicy};
+use crate::ws::reconnect::ReconnectConfig;
+use crate::ws::rules::AutoLinkConfig;
+use crate::ws::scenes::{SceneActivatedEvent, SceneConfig};
+use crate::ws::{DiscordConnections, DiscordStream, DiscordStreams, Relay, WebConnections, WebSocketServer};
 
 mod ws;
 mod web;
 mod bd;
 mod license;
 mod ds_installer;
+mod events;
+mod metrics;
+mod net;
+mod store;
+mod targets;
+mod tls;
 
 const NAME: &str = env!("CARGO_CRATE_NAME");
 const DEFAULT_WS_PORT: u16 = 8214;
@@ -38,6 +53,42 @@ const DEFAULT_WEB_PORT: u16 = 4651;
 struct Config {
     bd_path: Option<String>,
     web_port: u16,
+    /// Serve the WS endpoints on the web port (`/discord` and `/ws/{target}`) instead of a port of their own
+    #[serde(default)]
+    single_port: bool,
+    /// Listen address and client allowlist shared by the web and WS servers
+    #[serde(default)]
+    network: NetworkConfig,
+    /// HTTPS/WSS on both servers, with a generated certificate unless PEM files are given
+    #[serde(default)]
+    tls: TlsConfig,
+    /// Pings and idle timeout of the WS connections, dead targets and discord clients are dropped after it
+    #[serde(default)]
+    heartbeat: HeartbeatConfig,
+    /// How long the links to the streams of a disconnected discord client wait for it to come back
+    #[serde(default)]
+    reconnect: ReconnectConfig,
+    /// Optional tokens required from the target sockets and the HTTP API token, the discord socket always needs the plugin secret
+    #[serde(default)]
+    auth: AuthConfig,
+    /// Browser origins allowed on the WS endpoints besides the discord clients, OBS and the web server's own pages, and the names these pages are reached by
+    #[serde(default)]
+    origins: OriginConfig,
+    /// Targets declared from the app with their display name and description, keyed by target id
+    #[serde(default)]
+    targets: HashMap<String, TargetConfig>,
+    /// Named layouts switching many targets at once, keyed by scene name
+    #[serde(default)]
+    scenes: HashMap<String, SceneConfig>,
+    /// Moved to the session store, only read to migrate the config files of older versions
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    bindings: HashMap<String, String>,
+    /// Ordered rules linking new streams to the targets that aren't bound to a user
+    #[serde(default)]
+    auto_link: AutoLinkConfig,
+    /// Moved to the session store, only read to migrate the config files of older versions
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    display_options: HashMap<String, DisplayOptions>,
 }
 
 impl Default for Config {
@@ -45,6 +96,18 @@ impl Default for Config {
         Self {
             bd_path: Some(get_bd_path().get(0).expect("Failed to get BD path").to_string()),
             web_port: DEFAULT_WEB_PORT,
+            single_port: false,
+            network: NetworkConfig::default(),
+            tls: TlsConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            auth: AuthConfig::default(),
+            origins: OriginConfig::default(),
+            targets: HashMap::new(),
+            scenes: HashMap::new(),
+            bindings: HashMap::new(),
+            auto_link: AutoLinkConfig::default(),
+            display_options: HashMap::new(),
         }
     }
 }
@@ -63,8 +126,13 @@ impl Config {
     }
 }
 
+/// Directory of the config file, the session store and the generated certificate live next to it
+fn config_dir() -> PathBuf {
+    confy::get_configuration_file_path(NAME, None).unwrap().parent().expect("Config file has no parent directory").to_path_buf()
+}
+
 struct State {
-    config: PLMutex<Config>,
+    config: Arc<PLMutex<Config>>,
     bd_settings: PLMutex<BdSettings>,
 }
 
@@ -80,17 +148,58 @@ pub const DS_INVITE: &str = "https://discord.com/invite/MehYjUJGpA";
 #[tokio::main]
 async fn main() {
     init_logging();
+    // Before anything is spawned on it, the session store saves from its blocking threads
+    tauri::async_runtime::set(tokio::runtime::Handle::current());
     info!("Configuring Open ASAR...");
     configure_open_asar().await;
     info!("Configured Open ASAR");
 
-    let config = Config::load();
+    let mut config = Config::load();
+
+    let store = SessionStore::load(&config_dir());
+    if !config.bindings.is_empty() || !config.display_options.is_empty() {
+        info!("Moving the bindings and display options from the config to the session store");
+        store.migrate(std::mem::take(&mut config.bindings), std::mem::take(&mut config.display_options));
+        config.save();
+    }
+    if config.auth.api_token.is_none() {
+        info!("Generating the HTTP API token");
+        config.auth.api_token = Some(generate_secret());
+        config.save();
+    }
 
-    let bd_settings = PLMutex::new(BdSettings::load(format!("{}/plugins/DiscordSourcePlugin.config.json", config.bd_path.as_ref().expect("bd_path isn't defined").clone())).await.expect("Failed to load BD settings"));
+    let bd_settings_path = format!("{}/plugins/DiscordSourcePlugin.config.json", config.bd_path.as_ref().expect("bd_path isn't defined").clone());
+    let mut bd_settings = BdSettings::load(bd_settings_path.clone()).await.expect("Failed to load BD settings");
+
+    // The plugin reads the port to connect to from its settings, point it to the right server
+    let plugin_port = if config.single_port {
+        config.web_port
+    } else if bd_settings.ws_port == config.web_port {
+        DEFAULT_WS_PORT
+    } else {
+        bd_settings.ws_port
+    };
+    let mut changed = false;
+    if plugin_port != bd_settings.ws_port {
+        info!("Pointing the plugin to port {}", plugin_port);
+        bd_settings.ws_port = plugin_port;
+        changed = true;
+    }
+    // Settings written by older versions have no secret, the plugin picks it up from the same file
+    if bd_settings.secret.is_empty() {
+        info!("Generating the plugin secret");
+        bd_settings.secret = generate_secret();
+        changed = true;
+    }
+    if changed {
+        if let Err(e) = bd_settings.save(bd_settings_path).await {
+            error!("Failed to save BD settings: {}", e);
+        }
+    }
 
-    let config = PLMutex::new(config);
+    let bd_settings = PLMutex::new(bd_settings);
 
-    tauri::async_runtime::set(tokio::runtime::Handle::current());
+    let config = Arc::new(PLMutex::new(config));
 
     let quit = CustomMenuItem::new("quit".to_string(), "Quit");
     let show = CustomMenuItem::new("show".to_string(), "Show");
@@ -108,7 +217,8 @@ async fn main() {
         })
         .manage::<WebConnections>(Arc::new(RwLock::new(HashMap::new())))
         .manage::<DiscordStreams>(Arc::new(RwLock::new(HashMap::new())))
-        .manage::<DiscordConnection>(Arc::new(RwLock::new(None)))
+        .manage::<DiscordConnections>(Arc::new(RwLock::new(HashMap::new())))
+        .manage(Arc::new(store))
         .system_tray(SystemTray::new().with_menu(tray_menu))
         .on_system_tray_event(|app, event| match event {
             SystemTrayEvent::MenuItemClick { id, .. } => {
@@ -134,74 +244,117 @@ async fn main() {
             }
             _ => {}
         })
-        .invoke_handler(tauri::generate_handler![bd::get_bd_path, bd::install_plugin, get_config, get_streams, get_targets, open_ds_invite, check_license])
+        .invoke_handler(tauri::generate_handler![bd::get_bd_path, bd::install_plugin, get_config, set_display_options, get_streams, get_targets, create_target, rename_target, delete_target, get_scenes, activate_scene, open_ds_invite, check_license])
         .setup(|app| {
             let discord_streams: tauri::State<'_, DiscordStreams> = app.state();
             let web_connections: tauri::State<'_, WebConnections> = app.state();
-            let discord_connection: tauri::State<'_, DiscordConnection> = app.state();
+            let discord_connections: tauri::State<'_, DiscordConnections> = app.state();
+            let store: tauri::State<'_, Arc<SessionStore>> = app.state();
 
             let discord_streams = Arc::clone(&discord_streams);
             let web_connections = Arc::clone(&web_connections);
-            let discord_connection = Arc::clone(&discord_connection);
-
-            let mut ws_server = WebSocketServer::new(discord_streams, web_connections.clone(), discord_connection.clone());
-            let web_server = WebServer::new();
+            let discord_connections = Arc::clone(&discord_connections);
+            let store = Arc::clone(&store);
 
             let cfg: tauri::State<'_, State> = app.state();
+            let relay = Relay::new(discord_streams, web_connections, discord_connections, EventBus::new(), &cfg.config.lock(), store);
+            app.manage(relay.clone());
+
+            let mut ws_server = WebSocketServer::new(relay.clone());
+
+            let mut web_server = WebServer::new(relay.clone());
 
             app.listen_global("link-stream", {
-                let discord_connection = discord_connection.clone();
-                let web_connections = web_connections.clone();
+                let relay = relay.clone();
                 move |event| {
-                    let data: LinkEvent = serde_json::from_str(event.payload().unwrap()).unwrap();
                     info!("Link stream event: {:?}", event.payload());
-                    let web_connections = web_connections.clone();
-                    let discord_connection = discord_connection.clone();
+                    let Some(data) = event.payload().and_then(|payload| serde_json::from_str::<LinkEvent>(payload).ok()) else {
+                        error!("Invalid link stream event: {:?}", event.payload());
+                        return;
+                    };
+                    let relay = relay.clone();
                     tauri::async_runtime::spawn(async move {
-                        let source = data.source.unwrap();
-                        let _ = web_connections.write().await.get(&data.target).unwrap().linked_stream.write().insert(source.clone());
-                        let discord_connection = discord_connection.read().await;
-                        let discord_connection = discord_connection.as_ref().unwrap();
-                        let _ = discord_connection.ws_sink.lock().await.send(Message::Text(serde_json::to_string(
-                            &MessageType::Capture(CaptureEvent {
-                                stream_id: source,
-                            })
-                        ).unwrap())).await;
-                        info!("Sent capture event");
+                        let Some(source) = data.source else {
+                            return;
+                        };
+                        if let Err(e) = relay.link(&data.target, source).await {
+                            error!("Failed to link stream to {}: {}", data.target, e);
+                        }
                     });
                 }
             });
 
-            app.listen_global("unlink-stream", move |event| {
-                let data: LinkEvent = serde_json::from_str(event.payload().unwrap()).unwrap();
-                info!("Unlink stream event: {:?}", event.payload());
-                let web_connections = web_connections.clone();
-                let discord_connection = discord_connection.clone();
-                tauri::async_runtime::spawn(async move {
-                    let web_connections = web_connections.write().await;
-                    let Some(web_connection) = web_connections.get(&data.target) else {
-                        return;
-                    };
-                    let _ = web_connection.ws_sink.lock().await.send(Message::Text(serde_json::to_string(
-                        &MessageType::Unlink
-                    ).unwrap())).await;
-                    let Some(stream_id) = web_connection.linked_stream.write().take() else {
+            app.listen_global("unlink-stream", {
+                let relay = relay.clone();
+                move |event| {
+                    info!("Unlink stream event: {:?}", event.payload());
+                    let Some(data) = event.payload().and_then(|payload| serde_json::from_str::<LinkEvent>(payload).ok()) else {
+                        error!("Invalid unlink stream event: {:?}", event.payload());
                         return;
                     };
-                    let discord_connection = discord_connection.read().await;
-                    let discord_connection = discord_connection.as_ref().unwrap();
-                    let _ =  discord_connection.ws_sink.lock().await.send(Message::Text(serde_json::to_string(
-                        &MessageType::EndCapture(CaptureEvent {
-                            stream_id,
-                        })
-                    ).unwrap())).await;
-                    info!("Sent end capture event");
-                });
+                    let relay = relay.clone();
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(e) = relay.unlink(&data.target).await {
+                            error!("Failed to unlink {}: {}", data.target, e);
+                        }
+                    });
+                }
             });
 
             ws_server.set_window(app.get_window("main").unwrap());
 
-            bind_servers(ws_server, web_server, cfg.bd_settings.lock().ws_port, cfg.config.lock().web_port);
+            // Links made outside of the window (HTTP API, users followed to their new streams, scenes) are only published on the bus
+            tauri::async_runtime::spawn({
+                let window = app.get_window("main").unwrap();
+                let (_, mut receiver) = relay.events.subscribe(None);
+                async move {
+                    loop {
+                        match receiver.recv().await {
+                            Ok(envelope) => {
+                                if matches!(envelope.event, ServerEvent::TargetLinked(_) | ServerEvent::TargetUnlinked(_) | ServerEvent::TargetPending(_) | ServerEvent::SceneActivated(_)) {
+                                    let _ = window.emit(envelope.event.name(), envelope.event.payload());
+                                }
+                            }
+                            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                        }
+                    }
+                }
+            });
+            ws_server.set_heartbeat(cfg.config.lock().heartbeat.clone());
+
+            let auth = {
+                let config = cfg.config.lock();
+                let origins = OriginPolicy::new(&config.origins, config.web_port, config.network.listen_address, &config.tls.hostnames);
+                Auth::new(cfg.bd_settings.lock().secret.clone(), config.auth.clone(), origins)
+            };
+            ws_server.set_auth(auth.clone());
+            web_server.set_auth(auth);
+
+            let config_dir = config_dir();
+            let tls = {
+                let config = cfg.config.lock();
+                config.tls.acceptor(&config_dir, config.network.listen_address)
+            };
+            match tls {
+                Ok(Some(tls)) => {
+                    ws_server.set_tls(tls.clone());
+                    web_server.set_tls(tls);
+                }
+                Ok(None) => {}
+                Err(e) => error!("Failed to set up TLS, serving plain HTTP only: {:#}", e),
+            }
+
+            let ws_port = if cfg.config.lock().single_port {
+                let (upgrades_sender, upgrades_receiver) = tokio::sync::mpsc::channel(16);
+                ws_server.set_upgrades(upgrades_receiver);
+                web_server.set_upgrades(upgrades_sender);
+                None
+            } else {
+                Some(cfg.bd_settings.lock().ws_port)
+            };
+
+            bind_servers(ws_server, web_server, cfg.config.lock().network.clone(), ws_port, cfg.config.lock().web_port);
 
             let path = cfg.config.lock().bd_path.as_ref().expect("bd_path isn't defined").clone();
             tauri::async_runtime::spawn(async move {
@@ -247,41 +400,122 @@ async fn set_web_port(state: tauri::State<'_, State>, port: u16) -> Result<(), (
 }
 
 #[tauri::command]
-async fn get_targets(web_connections: tauri::State<'_, WebConnections>) -> Result<HashMap<String, Option<String>>, ()> {
-    let web_connections = web_connections.read().await;
-
-    let tasks = web_connections
-        .iter()
-        .map(|(id, conn)| {
-            let id = id.clone();
-            let linked_stream = conn.linked_stream.clone();
-            tokio::spawn(async move {
-                (id, linked_stream.read().clone())
-            })
+async fn set_display_options(relay: tauri::State<'_, Relay>, target: String, options: Option<DisplayOptions>) -> Result<(), String> {
+    if let Some(options) = &options {
+        options.validate()?;
+    }
+    relay.store.set_display_options(&target, options);
+    Ok(())
+}
+
+/// The declared targets, connected or not, along with the connected ones that weren't declared
+#[tauri::command]
+async fn get_targets(state: tauri::State<'_, State>, relay: tauri::State<'_, Relay>) -> Result<HashMap<String, TargetInfo>, ()> {
+    let connected = relay.targets().await;
+    let rules = relay.link_rules().await;
+    let pending = relay.pending.lock().keys().cloned().collect::<HashSet<_>>();
+
+    let config = state.config.lock();
+    let ids = config.targets.keys().chain(connected.keys()).cloned().collect::<HashSet<_>>();
+    Ok(ids.into_iter()
+        .map(|id| {
+            let declared = config.targets.get(&id);
+            let info = TargetInfo {
+                name: declared.map(|target| target.name.clone()).filter(|name| !name.is_empty()),
+                description: declared.map(|target| target.description.clone()).filter(|description| !description.is_empty()),
+                url: target_url(&config, &id),
+                declared: declared.is_some(),
+                connected: connected.contains_key(&id),
+                linked_stream: connected.get(&id).cloned().flatten(),
+                rule: rules.get(&id).cloned(),
+                pending: pending.contains(&id),
+                display_options: relay.store.target(&id).and_then(|stored| stored.display_options),
+            };
+            (id, info)
         })
-        .collect::<Vec<_>>();
+        .collect())
+}
+
+#[tauri::command]
+async fn create_target(state: tauri::State<'_, State>, id: String, name: String, description: String) -> Result<(), String> {
+    validate_id(&id)?;
+    let mut cfg = state.config.lock();
+    if cfg.targets.contains_key(&id) {
+        return Err(format!("Target {} already exists", id));
+    }
+    info!("Declaring target {}", id);
+    cfg.targets.insert(id, TargetConfig {
+        name,
+        description,
+    });
+    cfg.save();
+    Ok(())
+}
+
+/// Changes the display name and description, the id stays as it is in the url OBS loads
+#[tauri::command]
+async fn rename_target(state: tauri::State<'_, State>, id: String, name: String, description: String) -> Result<(), String> {
+    let mut cfg = state.config.lock();
+    let Some(target) = cfg.targets.get_mut(&id) else {
+        return Err(format!("Target {} isn't declared", id));
+    };
+    target.name = name;
+    target.description = description;
+    cfg.save();
+    Ok(())
+}
+
+/// Forgets the target along with its link, binding and display options. A page still connected stays until it closes
+#[tauri::command]
+async fn delete_target(state: tauri::State<'_, State>, relay: tauri::State<'_, Relay>, id: String) -> Result<(), String> {
+    {
+        let mut cfg = state.config.lock();
+        if cfg.targets.remove(&id).is_none() {
+            return Err(format!("Target {} isn't declared", id));
+        }
+        cfg.save();
+    }
+    info!("Deleted target {}", id);
+
+    let _ = relay.unlink(&id).await;
+    relay.store.forget(&id);
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_scenes(state: tauri::State<'_, State>) -> Result<HashMap<String, SceneConfig>, ()> {
+    Ok(state.config.lock().scenes.clone())
+}
 
-    Ok(join_all(tasks).await
-        .into_iter()
-        .map(|res| res.unwrap())
-        .collect::<HashMap<String, Option<String>>>()
-    )
+/// Resolves once every capture of the scene was asked for, with what changed for each target
+#[tauri::command]
+async fn activate_scene(state: tauri::State<'_, State>, relay: tauri::State<'_, Relay>, name: String) -> Result<SceneActivatedEvent, String> {
+    let scene = state.config.lock().scenes.get(&name).cloned();
+    let Some(scene) = scene else {
+        return Err(format!("No scene named {}", name));
+    };
+    Ok(relay.activate_scene(&name, &scene).await)
 }
 
 #[tauri::command]
-async fn get_streams(discord_streams: tauri::State<'_, DiscordStreams>) -> Result<HashMap<String, DiscordStream>, ()> {
-    let discord_streams = discord_streams.read().await;
-    Ok(discord_streams.clone())
+async fn get_streams(relay: tauri::State<'_, Relay>) -> Result<HashMap<String, DiscordStream>, ()> {
+    Ok(relay.streams().await)
 }
 
 //TODO: Handle errors sensing the error to the UI and asking the user to change the port
-fn bind_servers<R: tauri::Runtime>(mut ws_server: WebSocketServer<R>, mut web_server: WebServer, ws_port: u16, web_port: u16) {
-    tauri::async_runtime::spawn(async move {
-        ws_server.bind(ws_port).await.expect("Failed to bind WS server");
-        ws_server.accept_connections().await;
+/// Without a `ws_port` the WS server only gets the connections upgraded by the web server
+fn bind_servers<R: tauri::Runtime>(mut ws_server: WebSocketServer<R>, mut web_server: WebServer, network: NetworkConfig, ws_port: Option<u16>, web_port: u16) {
+    tauri::async_runtime::spawn({
+        let network = network.clone();
+        async move {
+            if let Some(ws_port) = ws_port {
+                ws_server.bind(&network, ws_port).await.expect("Failed to bind WS server");
+            }
+            ws_server.accept_connections().await;
+        }
     });
     tauri::async_runtime::spawn(async move {
-        web_server.bind(web_port, ws_port).await.expect("Failed to bind Web server");
+        web_server.bind(&network, web_port, ws_port).await.expect("Failed to bind Web server");
         web_server.run().await;
     });
 }
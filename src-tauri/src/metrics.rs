@@ -0,0 +1,114 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Clone, Copy)]
+pub enum Peer {
+    Discord,
+    Web,
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Process wide counters, gauges are read from the shared state when rendering
+#[derive(Default)]
+pub struct Metrics {
+    pub discord_connections: Counter,
+    pub discord_disconnections: Counter,
+    pub web_connections: Counter,
+    pub web_disconnections: Counter,
+    pub offers_to_web: Counter,
+    pub answers_to_discord: Counter,
+    pub ice_to_web: Counter,
+    pub ice_to_discord: Counter,
+    pub unhandled_from_discord: Counter,
+    pub unhandled_from_web: Counter,
+}
+
+impl Metrics {
+    pub fn connected(&self, peer: Peer) {
+        match peer {
+            Peer::Discord => self.discord_connections.inc(),
+            Peer::Web => self.web_connections.inc(),
+        }
+    }
+
+    pub fn disconnected(&self, peer: Peer) {
+        match peer {
+            Peer::Discord => self.discord_disconnections.inc(),
+            Peer::Web => self.web_disconnections.inc(),
+        }
+    }
+
+    pub fn unhandled(&self, peer: Peer) {
+        match peer {
+            Peer::Discord => self.unhandled_from_discord.inc(),
+            Peer::Web => self.unhandled_from_web.inc(),
+        }
+    }
+}
+
+/// Values sampled from the shared state at scrape time
+pub struct Gauges {
+    pub discord_clients: usize,
+    pub web_targets: usize,
+    pub linked_targets: usize,
+}
+
+/// Renders the metrics in the Prometheus text exposition format
+pub fn render(metrics: &Metrics, gauges: &Gauges) -> String {
+    let mut out = String::new();
+
+    write_metric(&mut out, "discord_source_discord_clients", "gauge", "Connected discord plugin clients", &[("", gauges.discord_clients as u64)]);
+    write_metric(&mut out, "discord_source_web_targets", "gauge", "Connected web targets", &[("", gauges.web_targets as u64)]);
+    write_metric(&mut out, "discord_source_linked_targets", "gauge", "Web targets linked to a discord stream", &[("", gauges.linked_targets as u64)]);
+
+    write_metric(&mut out, "discord_source_connections_total", "counter", "Accepted WS connections", &[
+        ("peer=\"discord\"", metrics.discord_connections.get()),
+        ("peer=\"web\"", metrics.web_connections.get()),
+    ]);
+    write_metric(&mut out, "discord_source_disconnections_total", "counter", "Closed WS connections", &[
+        ("peer=\"discord\"", metrics.discord_disconnections.get()),
+        ("peer=\"web\"", metrics.web_disconnections.get()),
+    ]);
+    write_metric(&mut out, "discord_source_signals_relayed_total", "counter", "Signalling messages relayed between discord and the web targets", &[
+        ("type=\"offer\",direction=\"discord_to_web\"", metrics.offers_to_web.get()),
+        ("type=\"answer\",direction=\"web_to_discord\"", metrics.answers_to_discord.get()),
+        ("type=\"ice\",direction=\"discord_to_web\"", metrics.ice_to_web.get()),
+        ("type=\"ice\",direction=\"web_to_discord\"", metrics.ice_to_discord.get()),
+    ]);
+    write_metric(&mut out, "discord_source_unhandled_messages_total", "counter", "Messages that couldn't be parsed as a known signal", &[
+        ("peer=\"discord\"", metrics.unhandled_from_discord.get()),
+        ("peer=\"web\"", metrics.unhandled_from_web.get()),
+    ]);
+
+    out
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
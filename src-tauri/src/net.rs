@@ -0,0 +1,63 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use ipnet::IpNet;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+
+/// Where the web and WS servers listen and which clients may connect to them
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Address both servers bind to, loopback by default so nothing is exposed to the LAN
+    pub listen_address: IpAddr,
+    /// When listening on an IPv6 address, also accept IPv4 clients on the same socket
+    pub dual_stack: bool,
+    /// Client networks allowed to connect, an empty list allows every client that can reach the listen address
+    pub allowed_clients: Vec<IpNet>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            dual_stack: false,
+            allowed_clients: vec![
+                "127.0.0.0/8".parse().unwrap(),
+                "::1/128".parse().unwrap(),
+            ],
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        if self.allowed_clients.is_empty() {
+            return true;
+        }
+
+        // Dual stack sockets report IPv4 clients as IPv4-mapped IPv6 addresses
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            IpAddr::V4(_) => addr,
+        };
+
+        self.allowed_clients.iter().any(|net| net.contains(&addr))
+    }
+
+    pub fn bind(&self, port: u16) -> std::io::Result<TcpListener> {
+        let addr = SocketAddr::new(self.listen_address, port);
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+        if addr.is_ipv6() {
+            socket.set_only_v6(!self.dual_stack)?;
+        }
+        // On Windows SO_REUSEADDR lets other processes steal the port, there the default behaviour is already fine
+        #[cfg(not(target_os = "windows"))]
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+
+        TcpListener::from_std(socket.into())
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex as PLMutex;
+use tracing::{error, info, warn};
+
+use crate::web::display::DisplayOptions;
+
+const FILE_NAME: &str = "session.json";
+
+/// What the app knows about a target, kept while its page isn't connected
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct StoredTarget {
+    /// Discord user the target follows to their new streams
+    pub user_id: Option<String>,
+    /// Stream the target was linked to last, linked again when the target connects and the stream is live
+    pub stream_id: Option<String>,
+    /// Auto-link rule that made that link
+    pub rule: Option<String>,
+    /// How the target page renders its stream, url query parameters override them
+    pub display_options: Option<DisplayOptions>,
+}
+
+impl StoredTarget {
+    fn is_empty(&self) -> bool {
+        self.user_id.is_none() && self.stream_id.is_none() && self.display_options.is_none()
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(default)]
+struct StoredSession {
+    targets: HashMap<String, StoredTarget>,
+}
+
+/// The layout of the show, saved next to the config file on every change and loaded at startup so the targets
+/// get their links back once the plugin and the pages reconnect. The config is what the operator sets up,
+/// the store is what the app last saw
+pub struct SessionStore {
+    path: Arc<PathBuf>,
+    session: Arc<PLMutex<StoredSession>>,
+    /// A save is waiting to run, the changes made meanwhile are written by it
+    save_scheduled: Arc<AtomicBool>,
+    /// Held while a save writes, so an older snapshot can't replace a newer one
+    writing: Arc<PLMutex<()>>,
+}
+
+impl SessionStore {
+    pub fn load(config_dir: &Path) -> Self {
+        let path = config_dir.join(FILE_NAME);
+        let session = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                error!("Failed to parse the session store {}, starting from an empty one: {}", path.display(), e);
+                StoredSession::default()
+            }),
+            Err(_) => {
+                info!("No session store at {}, starting from an empty one", path.display());
+                StoredSession::default()
+            }
+        };
+
+        Self {
+            path: Arc::new(path),
+            session: Arc::new(PLMutex::new(session)),
+            save_scheduled: Arc::new(AtomicBool::new(false)),
+            writing: Arc::new(PLMutex::new(())),
+        }
+    }
+
+    pub fn targets(&self) -> HashMap<String, StoredTarget> {
+        self.session.lock().targets.clone()
+    }
+
+    pub fn target(&self, target: &str) -> Option<StoredTarget> {
+        self.session.lock().targets.get(target).cloned()
+    }
+
+    /// Discord user each target follows
+    pub fn bindings(&self) -> HashMap<String, String> {
+        self.session.lock().targets.iter()
+            .filter_map(|(target, stored)| Some((target.clone(), stored.user_id.clone()?)))
+            .collect()
+    }
+
+    pub fn bind(&self, target: &str, user_id: Option<String>) {
+        self.update(target, |stored| stored.user_id = user_id);
+    }
+
+    /// The stream the target is linked to, `None` once it is unlinked
+    pub fn set_link(&self, target: &str, stream_id: Option<String>, rule: Option<String>) {
+        self.update(target, |stored| {
+            stored.stream_id = stream_id;
+            stored.rule = rule;
+        });
+    }
+
+    pub fn set_display_options(&self, target: &str, options: Option<DisplayOptions>) {
+        self.update(target, |stored| stored.display_options = options);
+    }
+
+    pub fn forget(&self, target: &str) {
+        self.update(target, |stored| *stored = StoredTarget::default());
+    }
+
+    /// Takes over the bindings and display options older versions kept in the config
+    pub fn migrate(&self, bindings: HashMap<String, String>, display_options: HashMap<String, DisplayOptions>) {
+        for (target, user_id) in bindings {
+            self.update(&target, |stored| stored.user_id = stored.user_id.take().or(Some(user_id)));
+        }
+        for (target, options) in display_options {
+            if let Err(e) = options.validate() {
+                warn!("Dropped the display options of target {} from the config: {}", target, e);
+                continue;
+            }
+            self.update(&target, |stored| stored.display_options = stored.display_options.take().or(Some(options)));
+        }
+    }
+
+    /// Applies the change and saves the store if it changed anything, targets left with nothing are forgotten
+    fn update(&self, target: &str, change: impl FnOnce(&mut StoredTarget)) {
+        let mut session = self.session.lock();
+        let previous = session.targets.get(target).cloned().unwrap_or_default();
+        let mut stored = previous.clone();
+        change(&mut stored);
+        if stored == previous {
+            return;
+        }
+
+        if stored.is_empty() {
+            session.targets.remove(target);
+        } else {
+            session.targets.insert(target.to_string(), stored);
+        }
+        drop(session);
+        self.schedule_save();
+    }
+
+    /// Saves on a blocking thread, off the lock. Changes made before the save takes its snapshot share its write
+    fn schedule_save(&self) {
+        if self.save_scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let path = self.path.clone();
+        let session = self.session.clone();
+        let save_scheduled = self.save_scheduled.clone();
+        let writing = self.writing.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            let _writing = writing.lock();
+            save_scheduled.store(false, Ordering::Release);
+            let content = serde_json::to_string_pretty(&*session.lock()).unwrap();
+            if let Err(e) = write_atomic(&path, content.as_bytes()) {
+                warn!("Failed to save the session store {}: {}", path.display(), e);
+            }
+        });
+    }
+}
+
+/// Writes a temporary file next to the store and renames it over the store, a crash mid-write leaves the previous one whole
+fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let temporary = path.with_extension("json.tmp");
+    let mut file = std::fs::File::create(&temporary)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temporary, path)
+}
@@ -0,0 +1,88 @@
+use std::net::IpAddr;
+
+use serde::Serialize;
+use ts_rs::TS;
+
+use crate::Config;
+use crate::web::display::DisplayOptions;
+
+/// A target declared from the app, so the show can be prepared before OBS opens its page. Keyed by target id
+/// in the config, the id is part of the url set in OBS and never changes
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TargetConfig {
+    /// Shown in the app instead of the id
+    pub name: String,
+    pub description: String,
+}
+
+/// A declared or connected target as listed by the app
+#[derive(Serialize, Debug, TS, Clone)]
+#[ts(export)]
+pub struct TargetInfo {
+    #[ts(optional)]
+    pub name: Option<String>,
+    #[ts(optional)]
+    pub description: Option<String>,
+    /// Page to add as a browser source in OBS
+    pub url: String,
+    /// Declared in the config, the others only exist while their page is connected
+    pub declared: bool,
+    pub connected: bool,
+    #[serde(rename = "linkedStream")]
+    #[ts(optional)]
+    pub linked_stream: Option<String>,
+    /// Auto-link rule that made the link
+    #[ts(optional)]
+    pub rule: Option<String>,
+    /// The linked stream went away with its discord client and the link waits for it
+    pub pending: bool,
+    /// Stored for the target, the url query overrides them
+    #[serde(rename = "displayOptions")]
+    #[ts(optional)]
+    pub display_options: Option<DisplayOptions>,
+}
+
+/// Target ids are a single path segment without an extension, the web server takes anything else for a file
+pub fn validate_id(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err("The target id can't be empty".to_string());
+    }
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')) {
+        return Err(format!("Target id {} can only contain letters, digits, - and _", id));
+    }
+    Ok(())
+}
+
+/// Url of the target page, with the token the target needs if one is configured
+pub fn target_url(config: &Config, id: &str) -> String {
+    let scheme = if config.tls.enabled { "https" } else { "http" };
+    let mut url = format!("{}://{}:{}/{}", scheme, web_host(config), config.web_port, id);
+    if let Some(token) = config.auth.target_tokens.get(id).or(config.auth.target_token.as_ref()) {
+        url.push_str("?token=");
+        url.extend(url::form_urlencoded::byte_serialize(token.as_bytes()));
+    }
+    url
+}
+
+/// The host the server is reached by: localhost when it only listens on loopback, the listen address when it listens on
+/// a single one, otherwise the first configured name. With TLS only the names of the certificate are used
+fn web_host(config: &Config) -> String {
+    match config.network.listen_address {
+        address if address.is_loopback() => return "localhost".to_string(),
+        IpAddr::V4(address) if !address.is_unspecified() => return address.to_string(),
+        IpAddr::V6(address) if !address.is_unspecified() => return format!("[{}]", address),
+        _ => {}
+    }
+
+    let origins_hostnames = if config.tls.enabled { &[][..] } else { &config.origins.hostnames[..] };
+    let hostname = origins_hostnames.iter()
+        .chain(&config.tls.hostnames)
+        .map(|hostname| hostname.trim())
+        .find(|hostname| !hostname.is_empty());
+    match hostname {
+        Some(hostname) if hostname.contains(':') => format!("[{}]", hostname.trim_start_matches('[').trim_end_matches(']')),
+        Some(hostname) => hostname.to_string(),
+        None => "localhost".to_string(),
+    }
+}
@@ -0,0 +1,243 @@
+use std::fs;
+use std::io::{BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context as _};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, CidrSubnet, DistinguishedName, DnType, GeneralSubtree, IsCa, KeyPair, NameConstraints, SanType};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::info;
+
+/// Time given to a client to send its first bytes and complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First byte of a TLS record carrying a handshake message, a ClientHello always starts with it
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+const CA_NAME: &str = "Discord Source Local CA";
+const CA_VALIDITY: time::Duration = time::Duration::days(10 * 365);
+/// Kept under the 398 days browsers accept for a server certificate
+const LEAF_VALIDITY: time::Duration = time::Duration::days(365);
+
+/// Optional TLS for the web and WS servers. Plain connections keep being accepted on the same ports,
+/// the discord plugin connects over `ws://localhost` and can't trust a locally generated certificate
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// User supplied PEM certificate chain, used along with `key_path` instead of the generated certificate
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    /// Extra host names and addresses the generated certificate is valid for, besides localhost and the listen address.
+    /// The local CA is limited to these names, changing them or the listen address creates a new CA in `<config_dir>/tls/ca.pem`
+    /// which has to be trusted again in the browsers and in OBS
+    pub hostnames: Vec<String>,
+}
+
+impl TlsConfig {
+    /// Builds the acceptor shared by both servers, `None` when TLS is disabled.
+    /// The generated certificates live in `<config_dir>/tls`, the CA is created once so it only has to be trusted once,
+    /// the leaf is issued again on every start to follow the configured names
+    pub fn acceptor(&self, config_dir: &Path, listen_address: IpAddr) -> anyhow::Result<Option<TlsAcceptor>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        let (cert_pem, key_pem) = match (&self.cert_path, &self.key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                info!("Using the TLS certificate {}", cert_path.display());
+                (
+                    fs::read_to_string(cert_path).with_context(|| format!("Failed to read {}", cert_path.display()))?,
+                    fs::read_to_string(key_path).with_context(|| format!("Failed to read {}", key_path.display()))?,
+                )
+            }
+            (None, None) => self.generate(&config_dir.join("tls"), listen_address)?,
+            _ => return Err(anyhow!("Both cert_path and key_path must be set to use a custom certificate")),
+        };
+
+        let certs = rustls_pemfile::certs(&mut BufReader::new(cert_pem.as_bytes()))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            return Err(anyhow!("No certificate found in the PEM file"));
+        }
+        let key = read_private_key(&key_pem)?;
+
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    /// Returns the PEM chain (leaf then CA) and the leaf key. The CA can only issue certificates for the names
+    /// it was created for, it is created again when they change and has to be trusted again
+    fn generate(&self, dir: &Path, listen_address: IpAddr) -> anyhow::Result<(String, String)> {
+        fs::create_dir_all(dir)?;
+        let ca_path = dir.join("ca.pem");
+        let ca_key_path = dir.join("ca-key.pem");
+        let ca_names_path = dir.join("ca-names.txt");
+
+        let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+        if !listen_address.is_unspecified() {
+            names.push(listen_address.to_string());
+        }
+        for hostname in &self.hostnames {
+            if !names.contains(hostname) {
+                names.push(hostname.clone());
+            }
+        }
+
+        let ca_names = fs::read_to_string(&ca_names_path).ok();
+        let ca_matches = ca_names.as_deref().map_or(false, |ca_names| ca_names.lines().eq(names.iter().map(String::as_str)));
+        if ca_path.exists() && !ca_matches {
+            info!("The names changed since the local CA was created, the new CA has to be trusted again");
+        }
+
+        let (ca_pem, ca) = if ca_path.exists() && ca_key_path.exists() && ca_matches {
+            let ca_pem = fs::read_to_string(&ca_path)?;
+            let key_pair = KeyPair::from_pem(&fs::read_to_string(&ca_key_path)?)?;
+            let ca = Certificate::from_params(CertificateParams::from_ca_cert_pem(&ca_pem, key_pair)?)?;
+            (ca_pem, ca)
+        } else {
+            info!("Generating a local CA in {}, trust it to open the targets over HTTPS", ca_path.display());
+            let now = time::OffsetDateTime::now_utc();
+            let mut params = CertificateParams::default();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name = DistinguishedName::new();
+            params.distinguished_name.push(DnType::CommonName, CA_NAME);
+            params.not_before = now - time::Duration::days(1);
+            params.not_after = now + CA_VALIDITY;
+            // A trusted CA whose key leaks could otherwise vouch for any site
+            params.name_constraints = Some(NameConstraints {
+                permitted_subtrees: names.iter().map(|name| match name.parse::<IpAddr>() {
+                    Ok(ip) => GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(ip, if ip.is_ipv4() { 32 } else { 128 })),
+                    Err(_) => GeneralSubtree::DnsName(name.clone()),
+                }).collect(),
+                excluded_subtrees: Vec::new(),
+            });
+            let ca = Certificate::from_params(params)?;
+
+            let ca_pem = ca.serialize_pem()?;
+            fs::write(&ca_path, &ca_pem)?;
+            write_private(&ca_key_path, &ca.serialize_private_key_pem())?;
+            fs::write(&ca_names_path, names.join("\n"))?;
+            (ca_pem, ca)
+        };
+
+        let now = time::OffsetDateTime::now_utc();
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "localhost");
+        params.subject_alt_names = names.iter().map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.clone()),
+        }).collect();
+        params.not_before = now - time::Duration::days(1);
+        params.not_after = now + LEAF_VALIDITY;
+        let leaf = Certificate::from_params(params)?;
+
+        let cert_pem = format!("{}{}", leaf.serialize_pem_with_signer(&ca)?, ca_pem);
+        let key_pem = leaf.serialize_private_key_pem();
+        fs::write(dir.join("cert.pem"), &cert_pem)?;
+        write_private(&dir.join("key.pem"), &key_pem)?;
+        info!("Issued a TLS certificate for {}", names.join(", "));
+
+        Ok((cert_pem, key_pem))
+    }
+}
+
+/// Writes a private key readable by the current user only
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // The mode only applies to new files, keys written by older versions were readable by everyone
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+fn read_private_key(pem: &str) -> anyhow::Result<rustls::PrivateKey> {
+    let mut reader = BufReader::new(pem.as_bytes());
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => {
+                return Ok(rustls::PrivateKey(key));
+            }
+            _ => {}
+        }
+    }
+    Err(anyhow!("No private key found in the PEM file"))
+}
+
+/// A connection accepted by one of the servers, TLS or not depending on what the client started with
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+/// Performs the TLS handshake when an acceptor is configured and the client opens with a ClientHello,
+/// anything else is handed back as a plain connection
+pub async fn accept(stream: TcpStream, acceptor: Option<&TlsAcceptor>) -> std::io::Result<MaybeTlsStream> {
+    let Some(acceptor) = acceptor else {
+        return Ok(MaybeTlsStream::Plain(stream));
+    };
+
+    let handshake = async {
+        let mut first = [0; 1];
+        if stream.peek(&mut first).await? == 0 || first[0] != TLS_HANDSHAKE_RECORD {
+            return Ok(MaybeTlsStream::Plain(stream));
+        }
+        Ok(MaybeTlsStream::Tls(Box::new(acceptor.accept(stream).await?)))
+    };
+
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out")))
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
@@ -1,66 +1,284 @@
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tracing::info;
+use std::sync::Arc;
 
-const HTML: &str = include_str!("../dist/web/index.html");
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{info, warn};
+
+use crate::net::NetworkConfig;
+use crate::store::SessionStore;
+use crate::tls::MaybeTlsStream;
+use crate::web::assets::{Asset, Assets};
+use crate::web::request::{keep_alive, ReadError, RequestReader};
+use crate::web::response::Body;
+use crate::web::router::Router;
+use crate::ws::auth::Auth;
+use crate::ws::{Relay, Upgrade};
+
+pub mod api;
+pub mod assets;
+pub mod display;
+pub mod request;
+pub mod response;
+pub mod router;
 
 pub struct WebServer {
     listener: Option<TcpListener>,
-    html: Option<String>,
+    network: NetworkConfig,
+    router: Arc<Router>,
+    relay: Relay,
+    upgrades: Option<mpsc::Sender<Upgrade>>,
+    auth: Option<Auth>,
+    tls: Option<TlsAcceptor>,
 }
 
 impl WebServer {
-    pub fn new() -> Self {
+    pub fn new(relay: Relay) -> Self {
         Self {
             listener: None,
-            html: None,
+            network: NetworkConfig::default(),
+            router: Arc::new(Router::new()),
+            relay,
+            upgrades: None,
+            auth: None,
+            tls: None,
         }
     }
 
+    pub fn set_tls(&mut self, tls: TlsAcceptor) {
+        self.tls = Some(tls);
+    }
 
-    pub async fn bind(&mut self, port: u16, ws_port: u16) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Webserver server listening on: {}", port);
-        let listener_task = TcpListener::bind(format!("0.0.0.0:{}", port));
+    /// Single port mode, WebSocket upgrades on `/discord` and `/ws/{target}` are handed to the WS server
+    pub fn set_upgrades(&mut self, upgrades: mpsc::Sender<Upgrade>) {
+        self.upgrades = Some(upgrades);
+    }
 
-        let html_task = tokio::spawn(async move {
-            let env_definitions = format!("<script>window.ws_port = {ws_port};</script>\n");
+    /// Tokens checked on the WebSocket upgrades, see `set_upgrades`, and hosts checked on the HTTP API
+    pub fn set_auth(&mut self, auth: Auth) {
+        self.auth = Some(auth);
+    }
 
-            format!("{}{}", env_definitions, HTML)
-        });
+    /// Without a `ws_port` the page connects back to the web server itself
+    pub async fn bind(&mut self, network: &NetworkConfig, port: u16, ws_port: Option<u16>) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Webserver server listening on: {}:{}", network.listen_address, port);
+        let listener = network.bind(port);
 
-        let (listener, html) = tokio::join!(listener_task, html_task);
+        let assets = tokio::task::spawn_blocking(Assets::load).await;
 
-        self.html = Some(html.unwrap());
+        self.router = Arc::new(build_router(assets?, Page {
+            html: Assets::index_html(),
+            ws_port,
+            store: self.relay.store.clone(),
+        }, self.relay.clone()));
 
         self.listener = Some(listener?);
+        self.network = network.clone();
         Ok(())
     }
 
     pub async fn run(&self) {
         if let Some(listener) = &self.listener {
-            while let Ok((stream, _)) = listener.accept().await {
-                tauri::async_runtime::spawn(handle_connection(stream, self.html.clone().unwrap()));
+            while let Ok((stream, addr)) = listener.accept().await {
+                if !self.network.is_allowed(addr.ip()) {
+                    warn!("Rejected HTTP connection from {}, not in the allowed clients", addr);
+                    continue;
+                }
+                let tls = self.tls.clone();
+                let router = self.router.clone();
+                let upgrades = self.upgrades.clone();
+                let auth = self.auth.clone();
+                tauri::async_runtime::spawn(async move {
+                    match crate::tls::accept(stream, tls.as_ref()).await {
+                        Ok(stream) => handle_connection(stream, router, upgrades, auth).await,
+                        Err(e) => warn!("TLS handshake with {} failed: {}", addr, e),
+                    }
+                });
             }
         }
     }
 }
 
-async fn handle_connection(mut stream: TcpStream, content: String) {
-    let buf_reader = BufReader::new(&mut stream);
+/// The target player page, rendered per request with the environment definitions it reads from `window`
+struct Page {
+    html: String,
+    ws_port: Option<u16>,
+    store: Arc<SessionStore>,
+}
+
+impl Page {
+    /// Display options stored for the target are the base, the url query overrides them
+    fn render(&self, target: &str, query: &str) -> Result<Asset, String> {
+        let stored = self.store.target(target).and_then(|stored| stored.display_options).unwrap_or_default();
+        let options = stored.with_query(query)?;
+
+        // Escaped so no value can close the script element
+        let env_definitions = format!(
+            "<script>window.ws_port = {}; window.display_options = {};</script>\n",
+            self.ws_port.map_or("null".to_string(), |port| port.to_string()),
+            serde_json::to_string(&options).unwrap().replace('<', "\\u003c")
+        );
+
+        Ok(Asset::dynamic("index.html", format!("{}{}", env_definitions, self.html).into_bytes()))
+    }
+}
 
-    if let Ok(Some(http_request)) = buf_reader.lines().next_line().await {
-        let path = http_request.split(' ').nth(1).unwrap();
+fn build_router(assets: Assets, page: Page, relay: Relay) -> Router {
+    let assets = Arc::new(assets);
+    let page = Arc::new(page);
+    let mut router = Router::new();
 
-        if path == "/favicon.ico" {
-            stream.write_all("HTTP/1.1 404 Not Found".as_bytes()).await.unwrap();
+    api::routes(&mut router, relay);
 
-            return;
+    // Files of the bundle are served under their own path, every other single segment path without an extension
+    // is a target id and gets the player page, which reads the id from its own location
+    router.fallback(move |request, _| {
+        let assets = assets.clone();
+        let page = page.clone();
+        async move {
+            if request.method() != Method::GET && request.method() != Method::HEAD {
+                return response::status(StatusCode::METHOD_NOT_ALLOWED);
+            }
+
+            let path = request.uri().path();
+            if let Some(asset) = assets.get(path) {
+                return asset.respond(request.headers());
+            }
+
+            let target = path.trim_start_matches('/');
+            if target.is_empty() || target.contains('/') || target.contains('.') {
+                return response::not_found();
+            }
+
+            match page.render(target, request.uri().query().unwrap_or_default()) {
+                Ok(page) => page.respond(request.headers()),
+                Err(e) => {
+                    warn!("Invalid display options for target {}: {}", target, e);
+                    let mut response = response::status(StatusCode::BAD_REQUEST);
+                    *response.body_mut() = Body::Full(format!("{}\n", e).into_bytes());
+                    response
+                }
+            }
         }
+    });
+
+    router
+}
+
+async fn handle_connection(stream: MaybeTlsStream, router: Arc<Router>, upgrades: Option<mpsc::Sender<Upgrade>>, auth: Option<Auth>) {
+    let mut reader = RequestReader::new(stream);
+
+    loop {
+        let request = match reader.next_request().await {
+            Ok(request) => request,
+            Err(ReadError::Closed) => break,
+            Err(ReadError::Io(e)) => {
+                warn!("Failed to read HTTP request: {}", e);
+                break;
+            }
+            Err(e) => {
+                let status = match e {
+                    ReadError::Malformed(reason) => {
+                        warn!("Rejected malformed HTTP request: {}", reason);
+                        StatusCode::BAD_REQUEST
+                    }
+                    _ => {
+                        warn!("Rejected HTTP request exceeding size limits");
+                        StatusCode::PAYLOAD_TOO_LARGE
+                    }
+                };
+                let _ = response::write(reader.get_mut(), response::status(status), false, false).await;
+                break;
+            }
+        };
+
+        if let Some(upgrades) = &upgrades {
+            if is_websocket_upgrade(&request) {
+                upgrade(reader, request, upgrades, auth.as_ref()).await;
+                return;
+            }
+        }
+
+        let keep_alive = keep_alive(&request);
+        let head_only = request.method() == Method::HEAD;
+
+        let authorized = match &auth {
+            Some(auth) if request.uri().path().starts_with("/api/") => auth.authorize_api(request.method(), request.uri(), request.headers()),
+            _ => Ok(()),
+        };
+        let response = match authorized {
+            Ok(()) => router.dispatch(request).await,
+            Err(status) => response::status(status),
+        };
+
+        match response::write(reader.get_mut(), response, head_only, keep_alive).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                warn!("Failed to write HTTP response: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn is_websocket_upgrade(request: &Request<Vec<u8>>) -> bool {
+    request.headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// Answers the WebSocket handshake for `/discord` and `/ws/{target}`, the WS server then takes the socket
+/// as if it had accepted it on its own port, the target id being the last path segment either way
+async fn upgrade(mut reader: RequestReader<MaybeTlsStream>, request: Request<Vec<u8>>, upgrades: &mpsc::Sender<Upgrade>, auth: Option<&Auth>) {
+    let path = request.uri().path();
+    let is_target = path.strip_prefix("/ws/").map_or(false, |target| !target.is_empty() && !target.contains('/'));
+    if request.method() != Method::GET || (path != "/discord" && !is_target) {
+        let _ = response::write(reader.get_mut(), response::not_found(), false, false).await;
+        return;
     }
 
-    const STATUS_LINE: &str = "HTTP/1.1 200 OK";
+    if let Some(Err(status)) = auth.map(|auth| auth.authorize(request.uri(), request.headers())) {
+        let _ = response::write(reader.get_mut(), response::status(status), false, false).await;
+        return;
+    }
+
+    let version = request.headers().get(header::SEC_WEBSOCKET_VERSION).and_then(|value| value.to_str().ok());
+    if version != Some("13") {
+        let mut response = response::status(StatusCode::UPGRADE_REQUIRED);
+        response.headers_mut().insert(header::SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        let _ = response::write(reader.get_mut(), response, false, false).await;
+        return;
+    }
 
-    let response = format!("{}\r\nContent-Length: {}\r\n\r\n{}", STATUS_LINE, content.len(), content);
+    let Some(key) = request.headers().get(header::SEC_WEBSOCKET_KEY) else {
+        let _ = response::write(reader.get_mut(), response::status(StatusCode::BAD_REQUEST), false, false).await;
+        return;
+    };
 
-    stream.write_all(response.as_bytes()).await.unwrap();
-}
\ No newline at end of file
+    let response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::SEC_WEBSOCKET_ACCEPT, derive_accept_key(key.as_bytes()))
+        .body(Body::empty())
+        .unwrap();
+    if let Err(e) = response::write(reader.get_mut(), response, false, false).await {
+        warn!("Failed to write WebSocket handshake response: {}", e);
+        return;
+    }
+
+    // Frames the client sent right behind its handshake were read along with it, the WS server reads them first
+    let (stream, buffered) = reader.into_parts();
+
+    info!("WS connection request: {:?}", path);
+    let ws_stream = WebSocketStream::from_partially_read(stream, buffered, Role::Server, None).await;
+    if upgrades.send(Upgrade { ws_stream, uri: path.to_string() }).await.is_err() {
+        warn!("WS server isn't accepting connections, dropped the upgrade on {}", path);
+    }
+}
@@ -0,0 +1,268 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use http::{header, HeaderValue, Request, Response, StatusCode};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::events::{Envelope, EventBus};
+use crate::metrics::{Gauges, METRICS};
+
+use crate::web::response;
+use crate::web::response::Body;
+use crate::web::router::Router;
+use crate::ws::{Relay, RelayError};
+
+#[derive(serde::Deserialize)]
+struct LinkRequest {
+    source: String,
+}
+
+#[derive(Serialize)]
+struct TargetState {
+    target: String,
+    #[serde(rename = "linkedStream")]
+    linked_stream: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    #[serde(rename = "discordConnected")]
+    discord_connected: bool,
+    #[serde(rename = "webTargets")]
+    web_targets: usize,
+    #[serde(rename = "linkedTargets")]
+    linked_targets: usize,
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: &'static str,
+    message: String,
+}
+
+/// JSON control API mirroring the tauri commands and the link-stream/unlink-stream events
+pub fn routes(router: &mut Router, relay: Relay) {
+    router.get("/api/streams", {
+        let relay = relay.clone();
+        move |_, _| {
+            let relay = relay.clone();
+            async move {
+                response::json(StatusCode::OK, &relay.streams().await)
+            }
+        }
+    });
+
+    router.get("/api/targets", {
+        let relay = relay.clone();
+        move |_, _| {
+            let relay = relay.clone();
+            async move {
+                response::json(StatusCode::OK, &relay.targets().await)
+            }
+        }
+    });
+
+    router.post("/api/targets/{id}/link", {
+        let relay = relay.clone();
+        move |request, params| {
+            let relay = relay.clone();
+            async move {
+                let target = params["id"].clone();
+                let data = match parse_json::<LinkRequest>(&request) {
+                    Ok(data) => data,
+                    Err(response) => return response,
+                };
+
+                info!("Link stream API request: {} -> {}", data.source, target);
+                match relay.link(&target, data.source.clone()).await {
+                    Ok(()) => response::json(StatusCode::OK, &TargetState {
+                        target,
+                        linked_stream: Some(data.source),
+                    }),
+                    Err(e) => relay_error(e),
+                }
+            }
+        }
+    });
+
+    router.get("/api/streams/{stream_id}/preview", {
+        let relay = relay.clone();
+        move |request, params| {
+            let relay = relay.clone();
+            async move {
+                let preview = relay.discord_streams.read().await
+                    .get(&params["stream_id"])
+                    .and_then(|stream| stream.preview.clone());
+                let Some(preview) = preview else {
+                    return error(StatusCode::NOT_FOUND, "preview_not_found", "No preview available for this stream");
+                };
+
+                let mut response = if response::etag_matches(request.headers(), &preview.etag) {
+                    response::not_modified()
+                } else {
+                    response::ok(&preview.content_type, preview.data.clone())
+                };
+                let headers = response.headers_mut();
+                headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+                if let Ok(etag) = HeaderValue::from_str(&format!("\"{}\"", preview.etag)) {
+                    headers.insert(header::ETAG, etag);
+                }
+                response
+            }
+        }
+    });
+
+    router.get("/api/events", {
+        let events = relay.events.clone();
+        move |request, _| {
+            let events = events.clone();
+            async move {
+                event_stream(&request, events)
+            }
+        }
+    });
+
+    router.get("/metrics", {
+        let relay = relay.clone();
+        move |_, _| {
+            let relay = relay.clone();
+            async move {
+                let gauges = sample_gauges(&relay).await;
+                response::ok("text/plain; version=0.0.4; charset=utf-8", crate::metrics::render(&METRICS, &gauges))
+            }
+        }
+    });
+
+    router.get("/healthz", {
+        let relay = relay.clone();
+        move |_, _| {
+            let relay = relay.clone();
+            async move {
+                let gauges = sample_gauges(&relay).await;
+                let discord_connected = gauges.discord_clients > 0;
+                let status = if discord_connected { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+                response::json(status, &Health {
+                    status: if discord_connected { "ok" } else { "degraded" },
+                    discord_connected,
+                    web_targets: gauges.web_targets,
+                    linked_targets: gauges.linked_targets,
+                })
+            }
+        }
+    });
+
+    router.post("/api/targets/{id}/unlink", move |_, params| {
+        let relay = relay.clone();
+        async move {
+            let target = params["id"].clone();
+
+            info!("Unlink stream API request: {}", target);
+            match relay.unlink(&target).await {
+                Ok(_) => response::json(StatusCode::OK, &TargetState {
+                    target,
+                    linked_stream: None,
+                }),
+                Err(e) => relay_error(e),
+            }
+        }
+    });
+}
+
+async fn sample_gauges(relay: &Relay) -> Gauges {
+    let targets = relay.targets().await;
+
+    Gauges {
+        discord_clients: relay.discord_connections.read().await.len(),
+        web_targets: targets.len(),
+        linked_targets: targets.values().filter(|linked_stream| linked_stream.is_some()).count(),
+    }
+}
+
+/// Interval of the comment lines sent to keep idle SSE connections from being dropped by proxies
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Server-Sent Events feed of the event bus, clients resume with Last-Event-ID (or `?lastEventId=`)
+/// and get the missed events replayed as long as they are still in the bus history
+fn event_stream(request: &Request<Vec<u8>>, events: EventBus) -> Response<Body> {
+    let last_event_id = request.headers()
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| request.uri().query().and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "lastEventId")
+                .map(|(_, value)| value.into_owned())
+        }))
+        .and_then(|value| value.trim().parse::<u64>().ok());
+
+    let (replay, mut receiver) = events.subscribe(last_event_id);
+
+    let body = async_stream::stream! {
+        yield b"retry: 3000\n\n".to_vec();
+
+        for envelope in replay {
+            yield format_event(&envelope);
+        }
+
+        let mut keep_alive = tokio::time::interval(SSE_KEEP_ALIVE);
+        keep_alive.tick().await;
+        loop {
+            tokio::select! {
+                envelope = receiver.recv() => match envelope {
+                    Ok(envelope) => yield format_event(&envelope),
+                    // The client reconnects with its last id and gets what it missed from the history
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("SSE client lagged behind by {} events, closing the stream", skipped);
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keep_alive.tick() => yield b": keep-alive\n\n".to_vec(),
+            }
+        }
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::Stream(body.boxed()))
+        .unwrap()
+}
+
+fn format_event(envelope: &Envelope) -> Vec<u8> {
+    format!("id: {}\nevent: {}\ndata: {}\n\n", envelope.id, envelope.event.name(), envelope.event.payload()).into_bytes()
+}
+
+pub fn error(status: StatusCode, error: &'static str, message: impl Into<String>) -> Response<Body> {
+    response::json(status, &ApiError {
+        error,
+        message: message.into(),
+    })
+}
+
+fn relay_error(e: RelayError) -> Response<Body> {
+    match e {
+        RelayError::TargetNotFound => error(StatusCode::NOT_FOUND, "target_not_found", e.to_string()),
+        RelayError::StreamNotFound => error(StatusCode::NOT_FOUND, "stream_not_found", e.to_string()),
+        RelayError::DiscordDisconnected => error(StatusCode::SERVICE_UNAVAILABLE, "discord_disconnected", e.to_string()),
+        RelayError::StreamBusy => error(StatusCode::CONFLICT, "stream_busy", e.to_string()),
+    }
+}
+
+/// Bodies must be sent as JSON, which also keeps plain cross-site form posts from reaching the API
+fn parse_json<T: serde::de::DeserializeOwned>(request: &Request<Vec<u8>>) -> Result<T, Response<Body>> {
+    let is_json = request.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("application/json"));
+    if !is_json {
+        return Err(error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", "Expected an application/json body"));
+    }
+
+    serde_json::from_slice(request.body()).map_err(|e| error(StatusCode::BAD_REQUEST, "invalid_body", e.to_string()))
+}
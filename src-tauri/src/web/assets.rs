@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use http::{header, HeaderMap, HeaderValue, Response};
+use rust_embed::RustEmbed;
+
+use crate::web::response;
+use crate::web::response::Body;
+
+/// The built `src-tauri/web` bundle, the target player page and everything it references
+#[derive(RustEmbed)]
+#[folder = "dist/web/"]
+struct Bundle;
+
+/// Files emitted by vite with a content hash in their name can be cached forever
+const IMMUTABLE_CACHE: &str = "public, max-age=31536000, immutable";
+const REVALIDATE_CACHE: &str = "no-cache";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Identity => "identity",
+        }
+    }
+}
+
+pub struct Asset {
+    content_type: &'static str,
+    cache_control: &'static str,
+    etag: String,
+    identity: Vec<u8>,
+    gzip: Option<Vec<u8>>,
+    brotli: Option<Vec<u8>>,
+}
+
+impl Asset {
+    pub fn new(path: &str, data: Vec<u8>, cache_control: &'static str) -> Self {
+        Self::build(path, data, cache_control, true)
+    }
+
+    /// Asset rendered per request, it's still revalidated through its ETag but compressing it every time isn't worth it
+    pub fn dynamic(path: &str, data: Vec<u8>) -> Self {
+        Self::build(path, data, REVALIDATE_CACHE, false)
+    }
+
+    fn build(path: &str, data: Vec<u8>, cache_control: &'static str, compress: bool) -> Self {
+        let content_type = response::content_type(path);
+        let etag = format!("{:x}", md5::compute(&data));
+
+        let (gzip, brotli) = if compress && is_compressible(content_type) {
+            (compress_gzip(&data), compress_brotli(&data))
+        } else {
+            (None, None)
+        };
+
+        Self {
+            content_type,
+            cache_control,
+            etag,
+            gzip: gzip.filter(|compressed| compressed.len() < data.len()),
+            brotli: brotli.filter(|compressed| compressed.len() < data.len()),
+            identity: data,
+        }
+    }
+
+    /// Builds the response for this asset honoring If-None-Match and Accept-Encoding
+    pub fn respond(&self, request_headers: &HeaderMap) -> Response<Body> {
+        let encoding = preferred_encoding(request_headers, self.gzip.is_some(), self.brotli.is_some());
+        let etag = match encoding {
+            Encoding::Identity => format!("\"{}\"", self.etag),
+            _ => format!("\"{}-{}\"", self.etag, encoding.name()),
+        };
+
+        let mut response = if response::etag_matches(request_headers, &self.etag) {
+            response::not_modified()
+        } else {
+            let body = match encoding {
+                Encoding::Brotli => self.brotli.clone(),
+                Encoding::Gzip => self.gzip.clone(),
+                Encoding::Identity => None,
+            }.unwrap_or_else(|| self.identity.clone());
+
+            let mut response = response::ok(self.content_type, body);
+            if encoding != Encoding::Identity {
+                response.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            }
+            response
+        };
+
+        let headers = response.headers_mut();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(self.cache_control));
+        headers.insert(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+        if let Ok(etag) = HeaderValue::from_str(&etag) {
+            headers.insert(header::ETAG, etag);
+        }
+        response
+    }
+}
+
+/// Every file of the embedded bundle keyed by its absolute url path, compressed once at startup
+pub struct Assets {
+    files: HashMap<String, Asset>,
+}
+
+impl Assets {
+    pub fn load() -> Self {
+        let files = Bundle::iter()
+            .filter(|path| path != "index.html")
+            .filter_map(|path| {
+                let file = Bundle::get(&path)?;
+                let cache_control = if path.starts_with("assets/") { IMMUTABLE_CACHE } else { REVALIDATE_CACHE };
+                Some((format!("/{}", path), Asset::new(&path, file.data.into_owned(), cache_control)))
+            })
+            .collect();
+
+        Self {
+            files
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Asset> {
+        self.files.get(path)
+    }
+
+    /// The target player page, returned separately since it gets the environment definitions injected per request
+    pub fn index_html() -> String {
+        Bundle::get("index.html")
+            .map(|file| String::from_utf8_lossy(&file.data).into_owned())
+            .unwrap_or_default()
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/") || content_type.starts_with("application/json") || content_type == "image/svg+xml" || content_type == "application/wasm"
+}
+
+fn compress_gzip(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+fn compress_brotli(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut output, 4096, 11, 22);
+        writer.write_all(data).ok()?;
+    }
+    Some(output)
+}
+
+/// Picks the best available encoding from Accept-Encoding, honoring q-values, br is preferred on ties
+fn preferred_encoding(headers: &HeaderMap, has_gzip: bool, has_brotli: bool) -> Encoding {
+    let mut best = (Encoding::Identity, 0.0);
+
+    for value in headers.get_all(header::ACCEPT_ENCODING).iter().filter_map(|value| value.to_str().ok()) {
+        for entry in value.split(',') {
+            let mut parts = entry.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if quality <= 0.0 {
+                continue;
+            }
+
+            let encoding = match name.as_str() {
+                "br" if has_brotli => Encoding::Brotli,
+                "gzip" if has_gzip => Encoding::Gzip,
+                _ => continue,
+            };
+
+            if quality > best.1 || (quality == best.1 && encoding == Encoding::Brotli) {
+                best = (encoding, quality);
+            }
+        }
+    }
+
+    best.0
+}
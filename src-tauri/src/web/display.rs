@@ -0,0 +1,120 @@
+use ts_rs::TS;
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone, Copy, PartialEq, Default)]
+#[ts(export)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    #[default]
+    Contain,
+    Cover,
+    Fill,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone, PartialEq)]
+#[ts(export)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How a target page renders its stream, stored per target in the session store and overridable from the target url
+#[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone, PartialEq)]
+#[ts(export)]
+#[serde(default)]
+pub struct DisplayOptions {
+    pub fit: Fit,
+    pub mirror: bool,
+    pub muted: bool,
+    /// css color used behind the video, "transparent" by default so OBS can composite the source
+    pub background: String,
+    #[ts(optional)]
+    pub crop: Option<Crop>,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        Self {
+            fit: Fit::Contain,
+            mirror: false,
+            muted: true,
+            background: "transparent".to_string(),
+            crop: None,
+        }
+    }
+}
+
+impl DisplayOptions {
+    /// Applies the query parameters of a target url on top of these options,
+    /// e.g. `fit=cover&mirror=1&muted=0&bg=transparent&crop=0,0,1920,1080`. Unknown parameters are ignored
+    pub fn with_query(mut self, query: &str) -> Result<Self, String> {
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "fit" => {
+                    self.fit = match value.as_ref() {
+                        "contain" => Fit::Contain,
+                        "cover" => Fit::Cover,
+                        "fill" => Fit::Fill,
+                        _ => return Err(format!("invalid fit \"{}\", expected contain, cover or fill", value)),
+                    }
+                }
+                "mirror" => self.mirror = parse_bool(&key, &value)?,
+                "muted" => self.muted = parse_bool(&key, &value)?,
+                "bg" => {
+                    if !is_valid_color(&value) {
+                        return Err(format!("invalid bg \"{}\", expected a color name or hex color", value));
+                    }
+                    self.background = value.into_owned();
+                }
+                "crop" => self.crop = Some(parse_crop(&value)?),
+                _ => {}
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Checks options that didn't come from a url, the same way the query parameters are checked
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_valid_color(&self.background) {
+            return Err(format!("invalid background \"{}\", expected a color name or hex color", self.background));
+        }
+        if let Some(crop) = &self.crop {
+            if crop.width == 0 || crop.height == 0 {
+                return Err("invalid crop, the width and height can't be 0".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => Err(format!("invalid {} \"{}\", expected 1 or 0", key, value)),
+    }
+}
+
+fn parse_crop(value: &str) -> Result<Crop, String> {
+    let parts = value.split(',').map(|part| part.trim().parse::<u32>()).collect::<Result<Vec<_>, _>>();
+
+    match parts.as_deref() {
+        Ok([x, y, width, height]) if *width > 0 && *height > 0 => Ok(Crop {
+            x: *x,
+            y: *y,
+            width: *width,
+            height: *height,
+        }),
+        _ => Err(format!("invalid crop \"{}\", expected x,y,width,height", value)),
+    }
+}
+
+/// Only named colors and hex colors are accepted since the value ends up in the page style
+fn is_valid_color(value: &str) -> bool {
+    match value.strip_prefix('#') {
+        Some(hex) => matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => !value.is_empty() && value.len() <= 32 && value.chars().all(|c| c.is_ascii_alphabetic()),
+    }
+}
@@ -0,0 +1,253 @@
+use std::time::Duration;
+
+use http::{HeaderName, HeaderValue, Method, Request, Version};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::warn;
+
+const MAX_HEADERS: usize = 64;
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// How long an idle keep-alive connection is kept open waiting for the next request
+pub const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// The peer closed the connection (or went idle) before sending a new request
+    Closed,
+    Malformed(&'static str),
+    TooLarge,
+    Io(std::io::Error),
+}
+
+/// Reads HTTP/1.1 requests one by one from a connection, keeping any pipelined bytes for the next read
+pub struct RequestReader<S> {
+    stream: S,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> RequestReader<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Gives back the connection along with the bytes read past the last request
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+
+    pub async fn next_request(&mut self) -> Result<Request<Vec<u8>>, ReadError> {
+        let head_len = loop {
+            if let Some(position) = self.buf.windows(4).position(|window| window == b"\r\n\r\n") {
+                break position + 4;
+            }
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(ReadError::TooLarge);
+            }
+            match tokio::time::timeout(KEEP_ALIVE_TIMEOUT, self.fill()).await {
+                Ok(Ok(0)) | Err(_) if self.buf.is_empty() => return Err(ReadError::Closed),
+                Ok(Ok(0)) | Err(_) => return Err(ReadError::Malformed("connection closed mid request")),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(ReadError::Io(e)),
+            }
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&self.buf[..head_len]) {
+            Ok(httparse::Status::Complete(_)) => {}
+            Ok(httparse::Status::Partial) => return Err(ReadError::Malformed("incomplete request head")),
+            Err(e) => {
+                warn!("Failed to parse HTTP request: {}", e);
+                return Err(ReadError::Malformed("invalid request head"));
+            }
+        }
+
+        let method = Method::from_bytes(parsed.method.unwrap_or_default().as_bytes()).map_err(|_| ReadError::Malformed("invalid method"))?;
+        let version = match parsed.version {
+            Some(0) => Version::HTTP_10,
+            _ => Version::HTTP_11,
+        };
+
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(parsed.path.unwrap_or("/"))
+            .version(version);
+
+        let mut content_length = 0;
+        for header in parsed.headers.iter() {
+            let name = HeaderName::from_bytes(header.name.as_bytes()).map_err(|_| ReadError::Malformed("invalid header name"))?;
+            let value = HeaderValue::from_bytes(header.value).map_err(|_| ReadError::Malformed("invalid header value"))?;
+            if name == http::header::CONTENT_LENGTH {
+                content_length = value.to_str().ok().and_then(|value| value.trim().parse().ok()).ok_or(ReadError::Malformed("invalid content length"))?;
+            }
+            if name == http::header::TRANSFER_ENCODING {
+                return Err(ReadError::Malformed("chunked request bodies are not supported"));
+            }
+            builder = builder.header(name, value);
+        }
+
+        if content_length > MAX_BODY_SIZE {
+            return Err(ReadError::TooLarge);
+        }
+
+        while self.buf.len() < head_len + content_length {
+            match self.fill().await {
+                Ok(0) => return Err(ReadError::Malformed("connection closed mid body")),
+                Ok(_) => {}
+                Err(e) => return Err(ReadError::Io(e)),
+            }
+        }
+
+        let body = self.buf[head_len..head_len + content_length].to_vec();
+        self.buf.drain(..head_len + content_length);
+
+        builder.body(body).map_err(|_| ReadError::Malformed("invalid request"))
+    }
+
+    async fn fill(&mut self) -> std::io::Result<usize> {
+        let mut chunk = [0; 4096];
+        let read = self.stream.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..read]);
+        Ok(read)
+    }
+}
+
+/// Whether the connection should stay open after answering this request
+pub fn keep_alive(request: &Request<Vec<u8>>) -> bool {
+    let connection = request.headers().get(http::header::CONNECTION).and_then(|value| value.to_str().ok()).map(|value| value.to_ascii_lowercase());
+
+    match request.version() {
+        Version::HTTP_10 => connection.map_or(false, |value| value.contains("keep-alive")),
+        _ => connection.map_or(true, |value| !value.contains("close")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+
+    /// Hands out one chunk per read, like a peer whose request arrives in several packets
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl Chunks {
+        fn new(chunks: &[&[u8]]) -> Self {
+            Self(chunks.iter().map(|chunk| chunk.to_vec()).collect())
+        }
+    }
+
+    impl AsyncRead for Chunks {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            if let Some(mut chunk) = self.0.pop_front() {
+                let read = chunk.len().min(buf.remaining());
+                buf.put_slice(&chunk[..read]);
+                if read < chunk.len() {
+                    self.0.push_front(chunk.split_off(read));
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_partial_requests() {
+        let mut reader = RequestReader::new(Chunks::new(&[
+            b"POST /api/targets/main/link HT",
+            b"TP/1.1\r\nHost: localhost\r\nContent-Len",
+            b"gth: 16\r\n\r",
+            b"\n{\"source\":",
+            b"\"a:b\"}",
+        ]));
+
+        let request = reader.next_request().await.unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri().path(), "/api/targets/main/link");
+        assert_eq!(request.headers()["host"], "localhost");
+        assert_eq!(request.body(), b"{\"source\":\"a:b\"}");
+        assert!(matches!(reader.next_request().await, Err(ReadError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn reads_pipelined_requests() {
+        let mut reader = RequestReader::new(Chunks::new(&[
+            b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nokGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.0\r\n\r\nleft",
+        ]));
+
+        let first = reader.next_request().await.unwrap();
+        assert_eq!((first.uri().path(), first.body().as_slice()), ("/a", b"ok".as_slice()));
+        let second = reader.next_request().await.unwrap();
+        assert_eq!(second.uri().path(), "/b");
+        assert!(keep_alive(&second));
+        let third = reader.next_request().await.unwrap();
+        assert_eq!(third.uri().path(), "/c");
+        assert!(!keep_alive(&third));
+
+        let (_, rest) = reader.into_parts();
+        assert_eq!(rest, b"left");
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_heads() {
+        let mut head = b"GET / HTTP/1.1\r\n".to_vec();
+        while head.len() <= MAX_HEAD_SIZE {
+            head.extend_from_slice(b"X-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n");
+        }
+        let mut reader = RequestReader::new(Chunks::new(&[&head]));
+        assert!(matches!(reader.next_request().await, Err(ReadError::TooLarge)));
+
+        let mut head = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..=MAX_HEADERS {
+            head.extend_from_slice(format!("X-Header-{}: a\r\n", i).as_bytes());
+        }
+        head.extend_from_slice(b"\r\n");
+        let mut reader = RequestReader::new(Chunks::new(&[&head]));
+        assert!(matches!(reader.next_request().await, Err(ReadError::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_bodies() {
+        let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+        let mut reader = RequestReader::new(Chunks::new(&[head.as_bytes()]));
+        assert!(matches!(reader.next_request().await, Err(ReadError::TooLarge)));
+    }
+
+    #[tokio::test]
+    async fn refuses_transfer_encoding() {
+        // Along with a Content-Length, a proxy reading the other one would see a different request after the body
+        let mut reader = RequestReader::new(Chunks::new(&[
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        ]));
+        assert!(matches!(reader.next_request().await, Err(ReadError::Malformed(_))));
+
+        let mut reader = RequestReader::new(Chunks::new(&[b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"]));
+        assert!(matches!(reader.next_request().await, Err(ReadError::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_content_lengths() {
+        let mut reader = RequestReader::new(Chunks::new(&[b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"]));
+        assert!(matches!(reader.next_request().await, Err(ReadError::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn reports_truncated_requests() {
+        let mut reader = RequestReader::new(Chunks::new(&[b"GET / HTTP/1.1\r\nHost: local"]));
+        assert!(matches!(reader.next_request().await, Err(ReadError::Malformed(_))));
+
+        let mut reader = RequestReader::new(Chunks::new(&[b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"]));
+        assert!(matches!(reader.next_request().await, Err(ReadError::Malformed(_))));
+    }
+}
@@ -0,0 +1,140 @@
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use http::{header, HeaderMap, HeaderValue, Response, StatusCode};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+const SERVER_NAME: &str = concat!("discord-source/", env!("CARGO_PKG_VERSION"));
+
+pub enum Body {
+    Full(Vec<u8>),
+    /// Written chunk by chunk as it's produced, the connection is closed when the stream ends
+    Stream(BoxStream<'static, Vec<u8>>),
+}
+
+impl Body {
+    pub fn empty() -> Self {
+        Body::Full(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(value: Vec<u8>) -> Self {
+        Body::Full(value)
+    }
+}
+
+pub fn ok(content_type: &str, body: impl Into<Vec<u8>>) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::Full(body.into()))
+        .unwrap()
+}
+
+pub fn status(status: StatusCode) -> Response<Body> {
+    let reason = status.canonical_reason().unwrap_or_default();
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::Full(format!("{} {}\n", status.as_u16(), reason).into_bytes()))
+        .unwrap()
+}
+
+pub fn not_found() -> Response<Body> {
+    status(StatusCode::NOT_FOUND)
+}
+
+pub fn json<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::Full(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+pub fn not_modified() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_MODIFIED;
+    response
+}
+
+/// Whether If-None-Match matches the entity tag, encoding suffixes like `-gzip` are ignored
+pub fn etag_matches(request_headers: &HeaderMap, etag: &str) -> bool {
+    request_headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/").trim_matches('"'))
+        .any(|tag| tag == "*" || tag.split('-').next() == Some(etag))
+}
+
+/// Returns the MIME type for the extension of the given path
+pub fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase()).unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serializes the response on the wire, the body is omitted for HEAD requests but Content-Length is kept.
+/// Returns whether the connection can be kept alive, which is never the case after a streamed body
+pub async fn write<S: AsyncWrite + Unpin>(stream: &mut S, mut response: Response<Body>, head_only: bool, keep_alive: bool) -> std::io::Result<bool> {
+    let keep_alive = keep_alive && matches!(response.body(), Body::Full(_));
+    let switching_protocols = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(header::SERVER, HeaderValue::from_static(SERVER_NAME));
+    // A protocol switch carries its own `Connection: Upgrade`
+    if !switching_protocols || !headers.contains_key(header::CONNECTION) {
+        headers.insert(header::CONNECTION, HeaderValue::from_static(if keep_alive { "keep-alive" } else { "close" }));
+    }
+    let no_body = response.status().is_informational() || response.status() == StatusCode::NO_CONTENT || response.status() == StatusCode::NOT_MODIFIED;
+    if let (false, Body::Full(body)) = (no_body, response.body()) {
+        let length = body.len();
+        response.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    }
+
+    let status = response.status();
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status.as_u16(), status.canonical_reason().unwrap_or_default());
+    for (name, value) in response.headers() {
+        head.push_str(name.as_str());
+        head.push_str(": ");
+        head.push_str(value.to_str().unwrap_or_default());
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    if !head_only && !no_body {
+        match response.into_body() {
+            Body::Full(body) => stream.write_all(&body).await?,
+            Body::Stream(mut chunks) => {
+                stream.flush().await?;
+                while let Some(chunk) = chunks.next().await {
+                    stream.write_all(&chunk).await?;
+                    stream.flush().await?;
+                }
+            }
+        }
+    }
+    stream.flush().await?;
+    Ok(keep_alive)
+}
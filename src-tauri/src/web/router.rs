@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+
+use crate::web::response;
+use crate::web::response::Body;
+
+pub type Params = HashMap<String, String>;
+pub type Handler = Arc<dyn Fn(Request<Vec<u8>>, Params) -> BoxFuture<'static, Response<Body>> + Send + Sync>;
+
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+impl Route {
+    /// Segments are compared and captured percent-decoded, a segment that isn't valid UTF-8 once decoded matches nothing
+    fn matches(&self, path: &[&str]) -> Option<Params> {
+        if path.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = Params::new();
+        for (segment, part) in self.segments.iter().zip(path) {
+            let part = percent_decode_str(part).decode_utf8().ok()?;
+            match segment {
+                Segment::Static(value) if *value == part => {}
+                Segment::Static(_) => return None,
+                Segment::Param(name) => {
+                    params.insert(name.clone(), part.into_owned());
+                }
+            }
+        }
+
+        Some(params)
+    }
+}
+
+/// Minimal path router, patterns are made of static segments and `{name}` parameters, e.g. `/api/targets/{id}/link`
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Handler>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<F, Fut>(&mut self, method: Method, pattern: &str, handler: F) -> &mut Self
+        where F: Fn(Request<Vec<u8>>, Params) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output=Response<Body>> + Send + 'static {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|segment| match segment.strip_prefix('{').and_then(|segment| segment.strip_suffix('}')) {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Static(segment.to_string()),
+            })
+            .collect();
+
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: Arc::new(move |request, params| handler(request, params).boxed()),
+        });
+        self
+    }
+
+    pub fn get<F, Fut>(&mut self, pattern: &str, handler: F) -> &mut Self
+        where F: Fn(Request<Vec<u8>>, Params) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output=Response<Body>> + Send + 'static {
+        self.route(Method::GET, pattern, handler)
+    }
+
+    pub fn post<F, Fut>(&mut self, pattern: &str, handler: F) -> &mut Self
+        where F: Fn(Request<Vec<u8>>, Params) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output=Response<Body>> + Send + 'static {
+        self.route(Method::POST, pattern, handler)
+    }
+
+    /// Handler for requests whose path matches no route
+    pub fn fallback<F, Fut>(&mut self, handler: F) -> &mut Self
+        where F: Fn(Request<Vec<u8>>, Params) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output=Response<Body>> + Send + 'static {
+        self.fallback = Some(Arc::new(move |request, params| handler(request, params).boxed()));
+        self
+    }
+
+    /// Finds the handler for the request, HEAD is served by GET routes and OPTIONS is answered with the allowed methods
+    pub async fn dispatch(&self, request: Request<Vec<u8>>) -> Response<Body> {
+        let path = split_path(request.uri().path());
+
+        let mut allowed = Vec::new();
+        let mut found = None;
+        for route in &self.routes {
+            let Some(params) = route.matches(&path) else {
+                continue;
+            };
+            if route.method == request.method() || (route.method == Method::GET && request.method() == Method::HEAD) {
+                found = Some((route.handler.clone(), params));
+                break;
+            }
+            allowed.push(route.method.clone());
+        }
+
+        if let Some((handler, params)) = found {
+            return handler(request, params).await;
+        }
+
+        if allowed.is_empty() && request.uri().path() != "*" {
+            return match &self.fallback {
+                Some(fallback) => fallback(request, Params::new()).await,
+                None => response::not_found(),
+            };
+        }
+
+        if allowed.contains(&Method::GET) {
+            allowed.push(Method::HEAD);
+        }
+        allowed.push(Method::OPTIONS);
+        let allow = allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+
+        let mut response = if request.method() == Method::OPTIONS {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NO_CONTENT;
+            response
+        } else {
+            response::status(StatusCode::METHOD_NOT_ALLOWED)
+        };
+        if let Ok(allow) = HeaderValue::from_str(&allow) {
+            response.headers_mut().insert(header::ALLOW, allow);
+        }
+        response
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|segment| !segment.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/api/streams/{id}/preview", |_, params: Params| async move {
+            let mut response = Response::new(Body::empty());
+            response.headers_mut().insert("x-id", HeaderValue::from_str(&params["id"]).unwrap());
+            response
+        });
+        router.post("/api/targets/{id}/unlink", |_, _| async move {
+            Response::new(Body::empty())
+        });
+        router
+    }
+
+    async fn dispatch(router: &Router, path: &str) -> Response<Body> {
+        request(router, Method::GET, path).await
+    }
+
+    async fn request(router: &Router, method: Method, path: &str) -> Response<Body> {
+        router.dispatch(Request::builder().method(method).uri(path).body(Vec::new()).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn decodes_params() {
+        let router = router();
+
+        let response = dispatch(&router, "/api/streams/client%3Astream/preview").await;
+        assert_eq!(response.headers()["x-id"], "client:stream");
+
+        let response = dispatch(&router, "/api/streams/client:stream/preview").await;
+        assert_eq!(response.headers()["x-id"], "client:stream");
+    }
+
+    #[tokio::test]
+    async fn serves_head_by_get() {
+        let response = request(&router(), Method::HEAD, "/api/streams/a/preview").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-id"], "a");
+    }
+
+    #[tokio::test]
+    async fn answers_wrong_methods_with_allow() {
+        let router = router();
+
+        let response = request(&router, Method::POST, "/api/streams/a/preview").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+
+        let response = request(&router, Method::GET, "/api/targets/a/unlink").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "POST, OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn answers_options() {
+        let router = router();
+
+        let response = request(&router, Method::OPTIONS, "/api/targets/a/unlink").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ALLOW], "POST, OPTIONS");
+
+        let response = request(&router, Method::OPTIONS, "*").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ALLOW], "OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn falls_back_on_unknown_paths() {
+        let mut router = router();
+        assert_eq!(dispatch(&router, "/unknown").await.status(), StatusCode::NOT_FOUND);
+
+        router.fallback(|_, _| async move {
+            response::status(StatusCode::IM_A_TEAPOT)
+        });
+        assert_eq!(dispatch(&router, "/unknown").await.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(request(&router, Method::POST, "/unknown").await.status(), StatusCode::IM_A_TEAPOT);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_utf8() {
+        let response = dispatch(&router(), "/api/streams/%FF/preview").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,75 +1,782 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures_util::{SinkExt, StreamExt};
 use futures_util::lock::Mutex;
 use futures_util::stream::{SplitSink, SplitStream};
-use parking_lot::RwLock as PLRwLock;
+use parking_lot::{Mutex as PLMutex, RwLock as PLRwLock};
 use serde::Serialize;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::WebSocketStream;
 use tracing::{error, info, warn};
 
-use crate::ws::message::MessageType;
+use crate::Config;
+use crate::store::SessionStore;
+use crate::events::{EventBus, ProtocolMismatchEvent, ServerEvent, StreamInfoEvent, TargetLinkEvent};
+use crate::metrics::{METRICS, Peer};
+use crate::net::NetworkConfig;
+use crate::tls::MaybeTlsStream;
+use crate::ws::auth::Auth;
+use crate::ws::captures::Captures;
+use crate::ws::clients::DEFAULT_CLIENT_ID;
+use crate::ws::heartbeat::{Heartbeat, HeartbeatConfig};
+use crate::ws::message::{AckEvent, CaptureEvent, ClientKind, ErrorCode, ErrorEvent, HelloEvent, MessageType, Signal, StreamKind};
+use crate::ws::preview::StreamPreview;
+use crate::ws::protocol::{PeerInfo, PROTOCOL_VERSION, REQUEST_IDS, SESSIONS};
+use crate::ws::reconnect::{DetachedLink, DetachedLinks, PendingLink, PendingLinks, ReconnectConfig};
+use crate::ws::requests::{Pending, Requests};
+use crate::ws::rules::AutoLink;
+use crate::ws::scenes::{SceneActivatedEvent, SceneConfig, SceneFailure, SceneSource};
+use crate::ws::session::Session;
 
+pub mod auth;
+pub mod captures;
+pub mod clients;
+pub mod heartbeat;
+pub mod origins;
 pub mod message;
+pub mod preview;
+pub mod protocol;
+pub mod reconnect;
+pub mod requests;
+pub mod rules;
+pub mod scenes;
+pub mod session;
 
 pub struct WebConnection {
-    pub ws_sink: Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>,
-    pub ws_stream: Arc<Mutex<SplitStream<WebSocketStream<TcpStream>>>>,
-    pub linked_stream: Arc<PLRwLock<Option<String>>>,
+    pub ws_sink: Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream>, Message>>>,
+    /// Signalling session with the linked stream, `None` while unlinked
+    pub session: PLMutex<Option<Session>>,
+    /// Set by the hello exchange, or to a legacy peer on the first other message
+    pub peer: PLRwLock<Option<PeerInfo>>,
+}
+
+impl WebConnection {
+    pub fn linked_stream(&self) -> Option<String> {
+        self.session.lock().as_ref().map(|session| session.stream_id.clone())
+    }
 }
 
 #[derive(Serialize, Clone)]
 pub struct DiscordStream {
-    #[serde(rename = "streamPreview")]
-    pub stream_preview: String,
+    /// Discord client the stream comes from, the stream is keyed by its id qualified with it
+    #[serde(rename = "clientId")]
+    pub client_id: String,
+    /// Discord user sharing the stream, targets bound to the user follow their new streams
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    /// Latest preview frame, served at `/api/streams/{stream_id}/preview` and serialized as its ETag
+    /// so the UI knows when to reload it
+    #[serde(rename = "previewEtag", serialize_with = "serialize_preview_etag")]
+    pub preview: Option<Arc<StreamPreview>>,
     pub nickname: String,
+    /// `None` when the plugin doesn't tell
+    pub kind: Option<StreamKind>,
+    /// When the stream was first reported, the auto-link rules take the oldest streams first
+    #[serde(skip)]
+    pub added_at: Instant,
 }
 
+fn serialize_preview_etag<S: serde::Serializer>(preview: &Option<Arc<StreamPreview>>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_some(&preview.as_ref().map(|preview| &preview.etag))
+}
+
+/// A connected discord client, its socket is only read by the client's connection task
 pub struct DiscordSplittedConnection {
-    pub ws_sink: Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>,
-    pub ws_stream: Arc<Mutex<SplitStream<WebSocketStream<TcpStream>>>>,
+    pub ws_sink: Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream>, Message>>>,
+    pub peer: Arc<PLRwLock<Option<PeerInfo>>>,
 }
 
+type WsSink = Mutex<SplitSink<WebSocketStream<MaybeTlsStream>, Message>>;
+
 pub type WebConnections = Arc<RwLock<HashMap<String, WebConnection>>>;
 pub type DiscordStreams = Arc<RwLock<HashMap<String, DiscordStream>>>;
-pub type DiscordConnection = Arc<RwLock<Option<DiscordSplittedConnection>>>;
+/// Keyed by the client id sent in the hello
+pub type DiscordConnections = Arc<RwLock<HashMap<String, DiscordSplittedConnection>>>;
+
+#[derive(Debug)]
+pub enum RelayError {
+    TargetNotFound,
+    StreamNotFound,
+    DiscordDisconnected,
+    /// The plugin has no sessions and the stream is already shown by another target
+    StreamBusy,
+}
+
+impl std::fmt::Display for RelayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelayError::TargetNotFound => write!(f, "No target connected with this id"),
+            RelayError::StreamNotFound => write!(f, "No discord stream with this id"),
+            RelayError::DiscordDisconnected => write!(f, "The discord plugin isn't connected"),
+            RelayError::StreamBusy => write!(f, "The discord plugin doesn't support sessions and the stream is already shown by another target"),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+/// Shared signalling state with the operations used by both the desktop UI and the HTTP API
+#[derive(Clone)]
+pub struct Relay {
+    pub web_connections: WebConnections,
+    pub discord_streams: DiscordStreams,
+    pub discord_connections: DiscordConnections,
+    pub events: EventBus,
+    pub requests: Requests,
+    pub captures: Captures,
+    /// Links waiting for their discord client to come back
+    pub pending: PendingLinks,
+    /// Links of the targets that aren't connected, kept for when they come back
+    pub detached: DetachedLinks,
+    /// Target to user bindings, links and display options kept across restarts
+    pub store: Arc<SessionStore>,
+    auto_link: Arc<AutoLink>,
+    reconnect: ReconnectConfig,
+    /// Held through a scene activation, so two of them don't interleave
+    activation: Arc<Mutex<()>>,
+}
+
+impl Relay {
+    /// The links saved before the last exit come back as the links of disconnected targets
+    pub fn new(discord_streams: DiscordStreams, web_connections: WebConnections, discord_connections: DiscordConnections, events: EventBus, config: &Config, store: Arc<SessionStore>) -> Self {
+        let detached = store.targets().into_iter()
+            .filter_map(|(target, stored)| Some((target, DetachedLink {
+                stream_id: stored.stream_id?,
+                rule: stored.rule,
+            })))
+            .collect();
+        Self {
+            web_connections,
+            discord_streams,
+            discord_connections,
+            events,
+            requests: Requests::new(),
+            captures: Captures::new(),
+            pending: PendingLinks::default(),
+            detached: Arc::new(PLMutex::new(detached)),
+            store,
+            auto_link: Arc::new(AutoLink::new(&config.auto_link)),
+            reconnect: config.reconnect.clone(),
+            activation: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub async fn streams(&self) -> HashMap<String, DiscordStream> {
+        self.discord_streams.read().await.clone()
+    }
+
+    /// Connected targets with the stream they are linked to
+    pub async fn targets(&self) -> HashMap<String, Option<String>> {
+        self.web_connections.read().await
+            .iter()
+            .map(|(id, connection)| (id.clone(), connection.linked_stream()))
+            .collect()
+    }
+
+    /// Targets linked by an auto-link rule with the name of the rule
+    pub async fn link_rules(&self) -> HashMap<String, String> {
+        self.web_connections.read().await
+            .iter()
+            .filter_map(|(id, connection)| Some((id.clone(), connection.session.lock().as_ref()?.rule.clone()?)))
+            .collect()
+    }
+
+    /// Links the target to the stream and binds it to the stream's user, so the target follows the user's next streams
+    pub async fn link(&self, target: &str, stream_id: String) -> Result<(), RelayError> {
+        let user_id = self.discord_streams.read().await.get(&stream_id).map(|stream| stream.user_id.clone());
+        self.link_stream(target, stream_id, None).await?;
+        if let Some(user_id) = user_id {
+            self.bind(target, Some(user_id));
+        }
+        Ok(())
+    }
+
+    /// Links the target to the stream and asks discord to capture it for the target, a previously linked stream is released.
+    /// `rule` is the auto-link rule that made the link
+    async fn link_stream(&self, target: &str, stream_id: String, rule: Option<String>) -> Result<(), RelayError> {
+        if let Some(previous) = self.attach(target, stream_id, rule).await? {
+            self.release(previous).await?;
+        }
+        Ok(())
+    }
+
+    /// Links the target to the stream and asks discord to capture it for the target. Returns the previous session of
+    /// the target for the caller to release, a stream that moves to another target in the meantime keeps its capture
+    async fn attach(&self, target: &str, stream_id: String, rule: Option<String>) -> Result<Option<Session>, RelayError> {
+        let Some((client_id, _)) = clients::split(&stream_id) else {
+            return Err(RelayError::StreamNotFound);
+        };
+        if !self.discord_connections.read().await.contains_key(client_id) {
+            return Err(RelayError::DiscordDisconnected);
+        }
+        if !self.discord_streams.read().await.contains_key(&stream_id) {
+            return Err(RelayError::StreamNotFound);
+        }
+
+        let session = Session::new(stream_id.clone(), rule.clone());
+        let session_id = session.id.clone();
+        let previous = self.web_connections.read().await
+            .get(target)
+            .ok_or(RelayError::TargetNotFound)?
+            .session.lock()
+            .replace(session);
+        self.pending.lock().remove(target);
+        // Counted before the previous session is released, relinking to the same stream doesn't end its capture
+        let first = self.captures.acquire(&stream_id);
+
+        let relinked = previous.as_ref().map_or(false, |previous| previous.stream_id == stream_id);
+
+        // Plugins without sessions run a single peer connection per stream, capturing again would take it from the other targets
+        if !first && !relinked && !self.discord_supports(&stream_id, SESSIONS).await {
+            warn!("The discord plugin doesn't support sessions, target {} can't watch stream {} along with other targets", target, stream_id);
+            self.restore(target, &session_id, &stream_id, previous).await;
+            return Err(RelayError::StreamBusy);
+        }
+
+        let captured = self.send_to_discord(MessageType::Capture(CaptureEvent {
+            stream_id: stream_id.clone(),
+            session_id: Some(session_id.clone()),
+        })).await;
+        if let Err(e) = captured {
+            self.restore(target, &session_id, &stream_id, previous).await;
+            return Err(e);
+        }
+        info!("Sent capture event");
+
+        // Only once the new stream is on its way, a failed capture leaves the target on its previous stream
+        if previous.is_some() && !relinked {
+            self.send_to_web(target, MessageType::Unlink).await;
+        }
+
+        self.publish_link(ServerEvent::TargetLinked(TargetLinkEvent {
+            target: target.to_string(),
+            stream_id: Some(stream_id),
+            rule,
+        }));
+        Ok(previous)
+    }
+
+    /// Undoes a link that couldn't be made, the target keeps what it showed unless another link replaced the session meanwhile
+    async fn restore(&self, target: &str, session_id: &str, stream_id: &str, mut previous: Option<Session>) {
+        if let Some(connection) = self.web_connections.read().await.get(target) {
+            let mut slot = connection.session.lock();
+            if slot.as_ref().map_or(false, |session| session.id == session_id) {
+                *slot = previous.take();
+            }
+        }
+        self.captures.release(stream_id);
+        if let Some(previous) = previous {
+            let _ = self.release(previous).await;
+        }
+    }
+
+    /// Switches every target of the scene at once. The streams the scene needs are all captured before any stream
+    /// it no longer needs is released, so a stream moving between targets is never captured again
+    pub async fn activate_scene(&self, name: &str, scene: &SceneConfig) -> SceneActivatedEvent {
+        let _activation = self.activation.lock().await;
+        info!("Activating scene {}", name);
+
+        let mut report = SceneActivatedEvent {
+            scene: name.to_string(),
+            ..Default::default()
+        };
+        let mut released = Vec::new();
+
+        let mut links = scene.links.iter().collect::<Vec<_>>();
+        links.sort_by_key(|(target, _)| *target);
+        for (target, source) in links {
+            let (stream_id, user_id) = match source {
+                SceneSource::User(user_id) => (self.user_stream(user_id).await, Some(user_id.clone())),
+                SceneSource::Stream(stream_id) => {
+                    let user_id = self.discord_streams.read().await.get(stream_id).map(|stream| stream.user_id.clone());
+                    (user_id.is_some().then(|| stream_id.clone()), user_id)
+                }
+                SceneSource::Unlinked => (None, None),
+            };
+            // A stream that isn't live leaves the binding as it is
+            if user_id.is_some() || *source == SceneSource::Unlinked {
+                self.bind(target, user_id);
+            }
+
+            if stream_id.is_none() && *source != SceneSource::Unlinked {
+                let reason = match source {
+                    SceneSource::User(user_id) => format!("User {} isn't streaming, the target will follow them", user_id),
+                    _ => RelayError::StreamNotFound.to_string(),
+                };
+                report.failed.push(SceneFailure {
+                    target: target.clone(),
+                    reason,
+                });
+                continue;
+            }
+            if self.current_link(target).await == stream_id {
+                report.unchanged.push(target.clone());
+                continue;
+            }
+
+            let Some(stream_id) = stream_id else {
+                let session = self.web_connections.read().await.get(target).and_then(|connection| connection.session.lock().take());
+                self.pending.lock().remove(target);
+                self.detached.lock().remove(target);
+                self.send_to_web(target, MessageType::Unlink).await;
+                released.extend(session);
+
+                self.publish_link(ServerEvent::TargetUnlinked(TargetLinkEvent {
+                    target: target.clone(),
+                    stream_id: None,
+                    rule: None,
+                }));
+                report.unlinked.push(target.clone());
+                continue;
+            };
+
+            match self.attach(target, stream_id.clone(), None).await {
+                Ok(previous) => {
+                    released.extend(previous);
+                    report.linked.push(target.clone());
+                }
+                // The target gets the stream once its page connects
+                Err(RelayError::TargetNotFound) => {
+                    self.pending.lock().remove(target);
+                    self.detached.lock().insert(target.clone(), DetachedLink {
+                        stream_id: stream_id.clone(),
+                        rule: None,
+                    });
+                    self.publish_link(ServerEvent::TargetLinked(TargetLinkEvent {
+                        target: target.clone(),
+                        stream_id: Some(stream_id),
+                        rule: None,
+                    }));
+                    report.linked.push(target.clone());
+                }
+                Err(e) => report.failed.push(SceneFailure {
+                    target: target.clone(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+
+        for session in released {
+            if let Err(e) = self.release(session).await {
+                warn!("Failed to release a stream of scene {}: {}", name, e);
+            }
+        }
+
+        info!("Activated scene {}: {} linked, {} unlinked, {} unchanged, {} failed", name, report.linked.len(), report.unlinked.len(), report.unchanged.len(), report.failed.len());
+        self.events.publish(ServerEvent::SceneActivated(report.clone()));
+        report
+    }
+
+    /// The stream the target shows, or would show once its page or its discord client is back
+    async fn current_link(&self, target: &str) -> Option<String> {
+        if let Some(stream_id) = self.web_connections.read().await.get(target).and_then(WebConnection::linked_stream) {
+            return Some(stream_id);
+        }
+        let pending = self.pending.lock().get(target).map(|link| link.stream_id.clone());
+        pending.or_else(|| self.detached.lock().get(target).map(|link| link.stream_id.clone()))
+    }
 
+    /// The oldest live stream of the user
+    async fn user_stream(&self, user_id: &str) -> Option<String> {
+        self.discord_streams.read().await
+            .iter()
+            .filter(|(_, stream)| stream.user_id == user_id)
+            .min_by_key(|(_, stream)| stream.added_at)
+            .map(|(stream_id, _)| stream_id.clone())
+    }
+
+    /// Unlinks the target and releases its stream, returns the stream it was linked to. The target stops following its user,
+    /// a target that isn't connected loses the link it would get back on its return
+    pub async fn unlink(&self, target: &str) -> Result<Option<String>, RelayError> {
+        self.bind(target, None);
+        let pending = self.pending.lock().remove(target).map(|link| link.stream_id);
+        let detached = self.detached.lock().remove(target).map(|link| link.stream_id);
+
+        let session = match self.web_connections.read().await.get(target) {
+            Some(connection) => connection.session.lock().take(),
+            None if pending.is_some() || detached.is_some() => None,
+            None => return Err(RelayError::TargetNotFound),
+        };
+        let stream_id = session.as_ref().map(|session| session.stream_id.clone()).or(pending).or(detached);
+
+        self.send_to_web(target, MessageType::Unlink).await;
+
+        if let Some(session) = session {
+            self.release(session).await?;
+        }
+
+        self.publish_link(ServerEvent::TargetUnlinked(TargetLinkEvent {
+            target: target.to_string(),
+            stream_id: stream_id.clone(),
+            rule: None,
+        }));
+        Ok(stream_id)
+    }
+
+    /// Links the targets bound to the users of the new streams, unless they still watch a stream that is live
+    pub async fn follow(&self, new_streams: &[(String, String)]) {
+        let bindings = self.store.bindings();
+        for (stream_id, user_id) in new_streams {
+            for (target, _) in bindings.iter().filter(|(_, bound)| *bound == user_id) {
+                let Some(linked_stream) = self.web_connections.read().await.get(target).map(WebConnection::linked_stream) else {
+                    continue;
+                };
+                if let Some(linked_stream) = linked_stream {
+                    if self.discord_streams.read().await.contains_key(&linked_stream) {
+                        continue;
+                    }
+                }
+
+                info!("Linking target {} to stream {} of user {}", target, stream_id, user_id);
+                if let Err(e) = self.link_stream(target, stream_id.clone(), None).await {
+                    warn!("Failed to link target {} to stream {}: {}", target, stream_id, e);
+                }
+            }
+        }
+    }
+
+    /// Ends the sessions on streams discord removed, there is no capture left to end. The targets stay bound to their user
+    pub async fn streams_removed(&self, stream_ids: &[String]) {
+        let mut unlinked = self.end_sessions(|stream_id| stream_ids.iter().any(|removed| removed == stream_id)).await
+            .into_iter()
+            .map(|(target, stream_id, _)| (target, stream_id))
+            .collect::<Vec<_>>();
+        self.detached.lock().retain(|target, link| {
+            if !stream_ids.contains(&link.stream_id) {
+                return true;
+            }
+            unlinked.push((target.clone(), link.stream_id.clone()));
+            false
+        });
+
+        for (target, stream_id) in unlinked {
+            self.publish_link(ServerEvent::TargetUnlinked(TargetLinkEvent {
+                target,
+                stream_id: Some(stream_id),
+                rule: None,
+            }));
+        }
+    }
+
+    /// Links a target that connected again to the stream it was linked to, with a fresh capture since its page starts
+    /// over. A target whose stream isn't live follows its user to their live stream
+    pub async fn reattach(&self, target: &str) {
+        let detached = self.detached.lock().remove(target);
+        let link = match detached {
+            Some(link) if self.discord_streams.read().await.contains_key(&link.stream_id) => Some((link.stream_id, link.rule)),
+            _ => match self.store.target(target).and_then(|stored| stored.user_id) {
+                Some(user_id) => self.user_stream(&user_id).await.map(|stream_id| (stream_id, None)),
+                None => None,
+            },
+        };
+        let Some((stream_id, rule)) = link else {
+            return;
+        };
+
+        info!("Target {} is back, linking it again to stream {}", target, stream_id);
+        if let Err(e) = self.link_stream(target, stream_id.clone(), rule).await {
+            warn!("Failed to link target {} again to stream {}: {}", target, stream_id, e);
+        }
+    }
+
+    /// Keeps the link of a target whose page went away and releases its stream, discord stops capturing it for nobody
+    pub async fn detach(&self, target: &str, session: Session) {
+        self.detached.lock().insert(target.to_string(), DetachedLink {
+            stream_id: session.stream_id.clone(),
+            rule: session.rule.clone(),
+        });
+        if let Err(e) = self.release(session).await {
+            warn!("Failed to release the stream of target {}: {}", target, e);
+        }
+    }
+
+    /// Drops the streams of a discord client that went away and ends the sessions on them. Their links are kept pending
+    /// through the reconnect grace period, the targets are linked again once the client reports the same streams or users
+    pub async fn discord_disconnected(&self, client_id: &str) {
+        let streams = {
+            let mut discord_streams = self.discord_streams.write().await;
+            let streams = discord_streams.iter()
+                .filter(|(_, stream)| stream.client_id == client_id)
+                .map(|(stream_id, stream)| (stream_id.clone(), stream.clone()))
+                .collect::<HashMap<_, _>>();
+            discord_streams.retain(|_, stream| stream.client_id != client_id);
+            streams
+        };
+
+        let mut ended = self.end_sessions(|stream_id| streams.contains_key(stream_id)).await;
+        self.detached.lock().retain(|target, link| {
+            if !streams.contains_key(&link.stream_id) {
+                return true;
+            }
+            ended.push((target.clone(), link.stream_id.clone(), link.rule.clone()));
+            false
+        });
+
+        let grace_period = self.reconnect.grace_period();
+        for (target, stream_id, rule) in ended {
+            let event = TargetLinkEvent {
+                target: target.clone(),
+                stream_id: Some(stream_id.clone()),
+                rule: rule.clone(),
+            };
+            match streams.get(&stream_id).filter(|_| !grace_period.is_zero()) {
+                Some(stream) => {
+                    info!("Target {} waits for stream {} to come back", target, stream_id);
+                    self.pending.lock().insert(target, PendingLink::new(stream_id, stream, rule));
+                    self.events.publish(ServerEvent::TargetPending(event));
+                }
+                None => self.publish_link(ServerEvent::TargetUnlinked(TargetLinkEvent {
+                    rule: None,
+                    ..event
+                })),
+            }
+        }
+
+        if !grace_period.is_zero() {
+            let relay = self.clone();
+            tauri::async_runtime::spawn(async move {
+                tokio::time::sleep(grace_period).await;
+                relay.expire_pending().await;
+            });
+        }
+    }
+
+    /// Links the pending targets again to their stream, or to the new stream of the same user, which captures it afresh
+    pub async fn resume(&self, new_streams: &[(String, String)]) {
+        if self.pending.lock().is_empty() {
+            return;
+        }
+
+        let resumed = {
+            let streams = self.discord_streams.read().await;
+            let mut pending = self.pending.lock();
+            let resumed = pending.iter()
+                .filter_map(|(target, link)| {
+                    let mut new_streams = new_streams.iter().map(|(stream_id, _)| stream_id);
+                    let stream_id = new_streams.clone().find(|stream_id| **stream_id == link.stream_id)
+                        .or_else(|| new_streams.find(|stream_id| streams.get(*stream_id).map_or(false, |stream| link.matches(stream_id, stream))))?;
+                    Some((target.clone(), stream_id.clone(), link.rule.clone()))
+                })
+                .collect::<Vec<_>>();
+            for (target, _, _) in &resumed {
+                pending.remove(target);
+            }
+            resumed
+        };
+
+        for (target, stream_id, rule) in resumed {
+            info!("Linking target {} back to stream {}", target, stream_id);
+            match self.link_stream(&target, stream_id.clone(), rule.clone()).await {
+                Ok(()) => {}
+                // The target's page went away in the meantime, it gets the stream when it comes back
+                Err(RelayError::TargetNotFound) => {
+                    self.detached.lock().insert(target, DetachedLink {
+                        stream_id,
+                        rule,
+                    });
+                }
+                Err(e) => warn!("Failed to link target {} back to stream {}: {}", target, stream_id, e),
+            }
+        }
+    }
+
+    /// Unlinks the targets still waiting past the grace period, the auto-link rules may have another stream for them
+    async fn expire_pending(&self) {
+        let grace_period = self.reconnect.grace_period();
+        let mut expired = Vec::new();
+        self.pending.lock().retain(|target, link| {
+            if link.since.elapsed() < grace_period {
+                return true;
+            }
+            expired.push((target.clone(), link.stream_id.clone()));
+            false
+        });
+        if expired.is_empty() {
+            return;
+        }
+
+        for (target, stream_id) in expired {
+            info!("Stream {} didn't come back, unlinking target {}", stream_id, target);
+            self.publish_link(ServerEvent::TargetUnlinked(TargetLinkEvent {
+                target,
+                stream_id: Some(stream_id),
+                rule: None,
+            }));
+        }
+        self.apply_rules().await;
+    }
+
+    /// Ends the sessions on the streams discord no longer has and tells their targets, there is no capture left to end.
+    /// Returns the targets with the stream they watched and the rule that linked them
+    async fn end_sessions(&self, gone: impl Fn(&str) -> bool) -> Vec<(String, String, Option<String>)> {
+        let ended = self.web_connections.read().await
+            .iter()
+            .filter_map(|(target, connection)| {
+                let mut session = connection.session.lock();
+                if !session.as_ref().map_or(false, |session| gone(&session.stream_id)) {
+                    return None;
+                }
+                Some((target.clone(), session.take()?))
+            })
+            .collect::<Vec<_>>();
+
+        let mut targets = Vec::with_capacity(ended.len());
+        for (target, session) in ended {
+            let stream_id = session.stream_id.clone();
+            let rule = session.rule.clone();
+            self.captures.release(&stream_id);
+            session.close();
+            self.send_to_web(&target, MessageType::Unlink).await;
+            targets.push((target, stream_id, rule));
+        }
+        targets
+    }
+
+    /// Links the connected targets that aren't bound to a user and don't watch a live stream, following the auto-link rules
+    pub async fn apply_rules(&self) {
+        if self.auto_link.is_empty() {
+            return;
+        }
+
+        let decisions = {
+            let streams = self.discord_streams.read().await;
+            let bindings = self.store.bindings();
+            let pending = self.pending.lock().keys().cloned().collect::<HashSet<_>>();
+            let mut free_targets = HashSet::new();
+            let mut linked = HashSet::new();
+            for (target, connection) in self.web_connections.read().await.iter() {
+                match connection.linked_stream().filter(|stream_id| streams.contains_key(stream_id)) {
+                    Some(stream_id) => {
+                        linked.insert(stream_id);
+                    }
+                    None if !bindings.contains_key(target) && !pending.contains(target) => {
+                        free_targets.insert(target.clone());
+                    }
+                    None => {}
+                }
+            }
+            self.auto_link.evaluate(&streams, &free_targets, &linked)
+        };
+
+        for decision in decisions {
+            info!("Auto-link rule {} links target {} to stream {}", decision.rule, decision.target, decision.stream_id);
+            if let Err(e) = self.link_stream(&decision.target, decision.stream_id.clone(), Some(decision.rule)).await {
+                warn!("Failed to link target {} to stream {}: {}", decision.target, decision.stream_id, e);
+            }
+        }
+    }
+
+    /// Saves the user the target follows, `None` forgets it
+    fn bind(&self, target: &str, user_id: Option<String>) {
+        self.store.bind(target, user_id);
+    }
+
+    /// Saves the link of the target to the store and publishes the change, pending links are kept as they are
+    fn publish_link(&self, event: ServerEvent) {
+        match &event {
+            ServerEvent::TargetLinked(link) => self.store.set_link(&link.target, link.stream_id.clone(), link.rule.clone()),
+            ServerEvent::TargetUnlinked(link) => self.store.set_link(&link.target, None, None),
+            _ => {}
+        }
+        self.events.publish(event);
+    }
+
+    /// Ends a target's session, discord only stops capturing the stream once no other target watches it
+    async fn release(&self, session: Session) -> Result<(), RelayError> {
+        let stream_id = session.stream_id.clone();
+        let session_id = session.id.clone();
+        session.close();
+
+        if self.captures.release(&stream_id) {
+            self.send_to_discord(MessageType::EndCapture(CaptureEvent {
+                stream_id,
+                session_id: None,
+            })).await?;
+            info!("Sent end capture event");
+        } else if self.discord_supports(&stream_id, SESSIONS).await {
+            self.send_to_discord(MessageType::EndSession(CaptureEvent {
+                stream_id,
+                session_id: Some(session_id),
+            })).await?;
+            info!("Sent end session event");
+        }
+        Ok(())
+    }
+
+    /// Whether the discord client of the stream negotiated the capability
+    async fn discord_supports(&self, stream_id: &str, capability: &str) -> bool {
+        let Some((client_id, _)) = clients::split(stream_id) else {
+            return false;
+        };
+        self.discord_connections.read().await
+            .get(client_id)
+            .and_then(|connection| connection.peer.read().as_ref().map(|peer| peer.supports(capability)))
+            .unwrap_or(false)
+    }
+
+    async fn send_to_web(&self, target: &str, message: MessageType) {
+        let web_connections = self.web_connections.read().await;
+        let Some(web_connection) = web_connections.get(target) else {
+            return;
+        };
+        let _ = send_signal(&web_connection.ws_sink, &web_connection.peer, &self.requests, message).await;
+    }
+
+    async fn send_to_discord(&self, message: MessageType) -> Result<(), RelayError> {
+        send_to_client(&self.discord_connections, &self.requests, message).await
+    }
+}
+
+
+/// Time given to a client to complete the WebSocket handshake, the connections are accepted one at a time
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct WebSocketServer<R: tauri::Runtime> {
     listener: Option<TcpListener>,
-    web_connections: WebConnections,
-    discord_streams: DiscordStreams,
-    discord_connection: DiscordConnection,
+    upgrades: Option<mpsc::Receiver<Upgrade>>,
+    tls: Option<TlsAcceptor>,
+    network: NetworkConfig,
+    heartbeat: HeartbeatConfig,
+    auth: Option<Auth>,
+    relay: Relay,
     window: Option<tauri::Window<R>>,
 }
 
 enum Status {
-    Ok(MessageType),
+    Ok(Signal),
     Unhandled(Message),
     Closed,
 }
 
 impl<R: tauri::Runtime> WebSocketServer<R> {
-    pub fn new(discord_streams: DiscordStreams, web_connections: WebConnections, discord_connection: DiscordConnection) -> Self {
+    pub fn new(relay: Relay) -> Self {
         Self {
             listener: None,
-            discord_connection,
-            discord_streams,
-            web_connections,
+            upgrades: None,
+            tls: None,
+            network: NetworkConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            auth: None,
+            relay,
             window: None,
         }
     }
 
-    pub async fn bind(&mut self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
-        info!("WS server listening on: {}", port);
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+    pub async fn bind(&mut self, network: &NetworkConfig, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        info!("WS server listening on: {}:{}", network.listen_address, port);
+        let listener = network.bind(port)?;
 
         self.listener = Some(listener);
+        self.network = network.clone();
         Ok(())
     }
 
@@ -77,220 +784,649 @@ impl<R: tauri::Runtime> WebSocketServer<R> {
         self.window = Some(window);
     }
 
+    pub fn set_tls(&mut self, tls: TlsAcceptor) {
+        self.tls = Some(tls);
+    }
+
+    pub fn set_heartbeat(&mut self, heartbeat: HeartbeatConfig) {
+        self.heartbeat = heartbeat;
+    }
+
+    /// Tokens required on the handshakes, checked before the connections are registered
+    pub fn set_auth(&mut self, auth: Auth) {
+        self.auth = Some(auth);
+    }
+
+    /// Receiver of the connections upgraded by the web server when both run on a single port
+    pub fn set_upgrades(&mut self, upgrades: mpsc::Receiver<Upgrade>) {
+        self.upgrades = Some(upgrades);
+    }
+
     pub async fn accept_connections(&mut self) {
         loop {
-            let listener = self.listener.as_ref().unwrap().accept().await;
-
-            let Ok((raw_tcp_stream, _)) = listener else {
-                continue;
+            let incoming = tokio::select! {
+                accepted = accept(self.listener.as_ref()) => Incoming::Accepted(accepted),
+                upgrade = recv_upgrade(&mut self.upgrades) => Incoming::Upgraded(upgrade),
             };
 
-            let mut uri = String::new();
+            // Each connection gets its own task for the handshakes and the registration, a slow client doesn't hold up the others
+            match incoming {
+                Incoming::Accepted(Ok((raw_tcp_stream, addr))) => {
+                    if !self.network.is_allowed(addr.ip()) {
+                        warn!("Rejected WS connection from {}, not in the allowed clients", addr);
+                        continue;
+                    }
+
+                    let tls = self.tls.clone();
+                    let auth = self.auth.clone();
+                    let (relay, heartbeat, window) = (self.relay.clone(), self.heartbeat.clone(), self.window.clone());
+                    tauri::async_runtime::spawn(async move {
+                        let stream = match crate::tls::accept(raw_tcp_stream, tls.as_ref()).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                warn!("TLS handshake with {} failed: {}", addr, e);
+                                return;
+                            }
+                        };
+
+                        let mut uri = String::new();
 
-            let ws_stream = tokio_tungstenite::accept_hdr_async(raw_tcp_stream, |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
-                info!("WS connection request: {:?}", request.uri());
-                uri = request.uri().to_string();
+                        let ws_stream = tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+                            // Only the path is logged and kept, the query carries the token
+                            info!("WS connection request: {:?}", request.uri().path());
+                            if let Some(Err(status)) = auth.as_ref().map(|auth| auth.authorize(request.uri(), request.headers())) {
+                                let mut response = ErrorResponse::new(Some(status.canonical_reason().unwrap_or_default().to_string()));
+                                *response.status_mut() = status;
+                                return Err(response);
+                            }
+                            uri = request.uri().path().to_string();
 
-                Ok(response)
-            }).await;
+                            Ok(response)
+                        });
 
-            let Ok(mut ws_stream) = ws_stream else {
-                continue;
+                        let Ok(Ok(ws_stream)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, ws_stream).await else {
+                            warn!("WS handshake with {} failed or timed out", addr);
+                            return;
+                        };
+
+                        Self::handle_connection(relay, heartbeat, window, ws_stream, uri).await;
+                    });
+                }
+                Incoming::Accepted(Err(_)) => {}
+                Incoming::Upgraded(Some(upgrade)) => {
+                    let (relay, heartbeat, window) = (self.relay.clone(), self.heartbeat.clone(), self.window.clone());
+                    tauri::async_runtime::spawn(Self::handle_connection(relay, heartbeat, window, upgrade.ws_stream, upgrade.uri));
+                }
+                Incoming::Upgraded(None) => self.upgrades = None,
+            }
+        }
+    }
+
+    async fn handle_connection(relay: Relay, heartbeat: HeartbeatConfig, window: Option<tauri::Window<R>>, mut ws_stream: WebSocketStream<MaybeTlsStream>, uri: String) {
+        if uri == "/discord" {
+            let Some(window) = window else {
+                error!("No window to report the discord connection to");
+                return;
             };
+            // The stream is only read by the connection task below, the sink is shared with the relay
+            let (ws_sink, mut ws_stream) = ws_stream.split();
+            let ws_sink = Arc::new(Mutex::new(ws_sink));
+            let peer = Arc::new(PLRwLock::new(None));
+            let mut heartbeat = Heartbeat::new(&heartbeat);
+            let discord_connections = relay.discord_connections.clone();
+            let events = relay.events.clone();
+            let requests = relay.requests.clone();
+            let discord_streams = relay.discord_streams.clone();
+            let web_connections = relay.web_connections.clone();
+            tauri::async_runtime::spawn(async move {
+                // The client is only known from its hello, plugins that don't send one all share the default client id
+                let mut first = Some(read_message(&mut ws_stream, &ws_sink, &mut heartbeat, DEFAULT_CLIENT_ID).await);
+                let client_id = match &first {
+                    Some(Status::Ok(Signal { message: MessageType::Hello(hello), .. })) => clients::client_id(hello.client_id.as_deref()),
+                    Some(Status::Closed) => return,
+                    _ => DEFAULT_CLIENT_ID.to_string(),
+                };
+                let peer_name = clients::peer_name(&client_id);
 
-            if uri == "/discord" {
-                let discord_connection = self.discord_connection.clone();
                 {
-                    let mut discord_connection = discord_connection.write().await;
-                    if discord_connection.is_some() {
-                        let _ = discord_connection.as_mut().unwrap().ws_sink.lock().await.close().await;
+                    let mut discord_connections = discord_connections.write().await;
+                    if let Some(previous) = discord_connections.remove(&client_id) {
+                        warn!("{} connected again, closing its previous connection", peer_name);
+                        let _ = previous.ws_sink.lock().await.close().await;
                     }
-                    let ws_stream_split = ws_stream.split();
-                    *discord_connection = Some(DiscordSplittedConnection {
-                        ws_sink: Arc::new(Mutex::new(ws_stream_split.0)),
-                        ws_stream: Arc::new(Mutex::new(ws_stream_split.1)),
+                    discord_connections.insert(client_id.clone(), DiscordSplittedConnection {
+                        ws_sink: ws_sink.clone(),
+                        peer: peer.clone(),
                     });
                 }
-                let window = self.window.clone().unwrap();
-                let discord_streams = self.discord_streams.clone();
-                let web_connections = self.web_connections.clone();
-                tauri::async_runtime::spawn(async move {
-                    loop {
-                        let discord_connection = discord_connection.clone();
+                info!("Discord connection established: {}", client_id);
+                METRICS.connected(Peer::Discord);
 
-                        let status = match discord_connection.read().await.as_ref() {
-                            None => {
-                                warn!("Discord connection is None, but we're still trying to read from it, stopping loop");
-                                Status::Closed
+                loop {
+                    let status = match first.take() {
+                        Some(status) => status,
+                        None => read_message(&mut ws_stream, &ws_sink, &mut heartbeat, &peer_name).await,
+                    };
+
+                    match status {
+                        Status::Ok(signal) => {
+                            if !matches!(signal.message, MessageType::Hello(_)) {
+                                check_legacy(&peer_name, &peer, &events, &window);
                             }
-                            Some(discord_connection) => {
-                                match discord_connection.ws_stream.lock().await.next().await {
-                                    None | Some(Err(_)) => {
-                                        Status::Closed
-                                    }
-                                    Some(Ok(msg)) => {
-                                        handle_message(msg)
+
+                            let request_id = signal.request_id;
+                            let result = match signal.message {
+                                MessageType::Hello(hello) => {
+                                    handle_hello(hello, ClientKind::Discord, &peer_name, &peer, &ws_sink, &events, &window).await;
+                                    continue;
+                                }
+                                MessageType::Remove(mut streams) => {
+                                    info!("Removed stream: {:?}", streams);
+                                    for stream in &mut streams {
+                                        stream.stream_id = clients::qualify(&client_id, &stream.stream_id);
+                                        discord_streams.write().await.remove(&stream.stream_id);
                                     }
+
+                                    let stream_ids = streams.iter().map(|stream| stream.stream_id.clone()).collect::<Vec<_>>();
+                                    events.emit(&window, ServerEvent::StreamRemoved(streams));
+                                    relay.streams_removed(&stream_ids).await;
+                                    relay.apply_rules().await;
+                                    Ok(())
                                 }
-                            }
-                        };
+                                MessageType::UpdateUserInfo(user_infos) => {
+                                    let mut updates = Vec::with_capacity(user_infos.len());
+                                    let mut new_streams = Vec::new();
+                                    for user_info in user_infos {
+                                        let stream_id = clients::qualify(&client_id, &user_info.stream_id);
+                                        let mut discord_streams = discord_streams.write().await;
+                                        let old_value = discord_streams.get(&stream_id);
+
+                                        let preview = match StreamPreview::from_data_url(&user_info.info.stream_preview) {
+                                            Some(preview) => Some(Arc::new(preview)),
+                                            None => {
+                                                warn!("Invalid preview for stream: {:?}", stream_id);
+                                                old_value.and_then(|stream| stream.preview.clone())
+                                            }
+                                        };
+
+                                        let stream_info = DiscordStream {
+                                            client_id: client_id.clone(),
+                                            user_id: user_info.user_id.clone(),
+                                            preview,
+                                            nickname: user_info.info.nickname,
+                                            kind: user_info.kind.or_else(|| old_value.and_then(|stream| stream.kind)),
+                                            added_at: old_value.map_or_else(Instant::now, |stream| stream.added_at),
+                                        };
 
-                        match status {
-                            Status::Ok(event) => {
-                                match event {
-                                    MessageType::Remove(streams) => {
-                                        info!("Removed stream: {:?}", streams);
-                                        for stream in &streams {
-                                            discord_streams.write().await.remove(&stream.stream_id.to_string());
+                                        updates.push(StreamInfoEvent {
+                                            stream_id: stream_id.clone(),
+                                            client_id: client_id.clone(),
+                                            user_id: user_info.user_id.clone(),
+                                            nickname: stream_info.nickname.clone(),
+                                            preview_etag: stream_info.preview.as_ref().map(|preview| preview.etag.clone()),
+                                            kind: stream_info.kind,
+                                        });
+
+                                        if discord_streams.insert(stream_id.clone(), stream_info).is_none() {
+                                            info!("Added stream: {:?}", stream_id);
+                                            new_streams.push((stream_id, user_info.user_id));
+                                        } else{
+                                            info!("Updated stream: {:?}", stream_id);
                                         }
+                                    }
+                                    events.emit(&window, ServerEvent::UserInfoUpdate(updates));
+                                    relay.resume(&new_streams).await;
+                                    relay.follow(&new_streams).await;
+                                    relay.apply_rules().await;
+                                    Ok(())
+                                }
+                                MessageType::ICE(ice) => {
+                                    info!("ICE: {:?}", ice);
 
-                                        window.emit("stream-removed", streams.clone()).unwrap();
+                                    let mut message = MessageType::ICE(ice);
+                                    clients::qualify_message(&client_id, &mut message);
+                                    let result = forward_to_target(&web_connections, &requests, message).await;
+                                    if result.is_ok() {
+                                        METRICS.ice_to_web.inc();
                                     }
-                                    MessageType::UpdateUserInfo(user_infos) => {
-                                        for user_info in &user_infos {
-                                            let stream_info = DiscordStream {
-                                                stream_preview: user_info.info.stream_preview.clone(),
-                                                nickname: user_info.info.nickname.clone(),
-                                            };
-
-                                            let old_value = discord_streams.write().await.insert(user_info.stream_id.clone(), stream_info);
-
-                                            if old_value.is_none() {
-                                                info!("Added stream: {:?}", user_info.stream_id);
-                                            } else{
-                                                info!("Updated stream: {:?}", user_info.stream_id);
-                                            }
+                                    result
+                                }
+                                MessageType::Offer(offer) => {
+                                    info!("Offer: {:?}", offer);
+
+                                    let mut message = MessageType::Offer(offer);
+                                    clients::qualify_message(&client_id, &mut message);
+                                    let result = forward_to_target(&web_connections, &requests, message).await;
+                                    if result.is_ok() {
+                                        METRICS.offers_to_web.inc();
+                                    }
+                                    result
+                                }
+                                MessageType::Ack(ack) => {
+                                    handle_ack(&requests, &peer_name, ack);
+                                    continue;
+                                }
+                                MessageType::Error(error) => {
+                                    // The plugin failed a capture or a negotiation, the targets waiting on that stream are told why
+                                    let pending = handle_error(&requests, &peer_name, &error);
+                                    if let Some(Pending { stream_id: Some(stream_id), session_id, .. }) = pending {
+                                        let stream_id = clients::qualify(&client_id, &stream_id);
+                                        let web_connections = web_connections.read().await;
+                                        let targets = web_connections.values().filter(|connection| {
+                                            connection.session.lock().as_ref().map_or(false, |session| session.matches(&stream_id, session_id.as_deref()))
+                                        });
+                                        for connection in targets {
+                                            let _ = send_signal(&connection.ws_sink, &connection.peer, &requests, MessageType::Error(ErrorEvent {
+                                                request_id: None,
+                                                ..error.clone()
+                                            })).await;
                                         }
-                                        window.emit("user-info-update", user_infos).unwrap();
                                     }
-                                    MessageType::ICE(ice) => {
-                                        info!("ICE: {:?}", ice);
+                                    continue;
+                                }
+                                event => {
+                                    error!("Invalid signal from discord: {:?}", event);
+                                    Err(ErrorEvent::new(ErrorCode::UnexpectedMessage, format!("Discord isn't expected to send {}", event.name())))
+                                }
+                            };
 
-                                        let web_connections = web_connections.read().await;
+                            reply(&ws_sink, request_id, result).await;
+                        }
+                        Status::Unhandled(msg) => {
+                            warn!("Unhandled message from discord: {:?}", msg);
+                            METRICS.unhandled(Peer::Discord);
+                            let (request_id, error) = invalid_message(&msg);
+                            reply(&ws_sink, request_id, Err(error)).await;
+                        }
+                        Status::Closed => {
+                            info!("Discord connection closed: {}", client_id);
+                            METRICS.disconnected(Peer::Discord);
+                            // A client that connected again already replaced this connection, its streams are kept
+                            let mut discord_connections = discord_connections.write().await;
+                            if discord_connections.get(&client_id).map_or(false, |connection| Arc::ptr_eq(&connection.ws_sink, &ws_sink)) {
+                                discord_connections.remove(&client_id);
+                                drop(discord_connections);
+                                relay.discord_disconnected(&client_id).await;
+                                events.emit(&window, ServerEvent::DiscordDisconnected(client_id));
+                            }
+                            break;
+                        }
+                    }
+                };
+            });
+        } else {
+            let id = uri.split('/').last().unwrap_or_default();
+            if id.is_empty() {
+                warn!("Invalid web connection request: {:?}", uri);
+                let _ = ws_stream.close(None).await;
+                return;
+            }
+            let Some(window) = window else {
+                error!("No window to report the web connection to");
+                return;
+            };
 
-                                        let connection = web_connections.values().find(|connection| {
-                                            connection.linked_stream.read().is_some() && connection.linked_stream.read().as_ref().unwrap() == ice.stream_id.as_ref().expect("ICE stream id is none on ice from discord")
-                                        }).expect("No web connection found for ice from discord");
+            // Checked and registered under the same lock, two pages connecting with the same id at once can't both get in
+            let mut web_connections = relay.web_connections.write().await;
+            if web_connections.contains_key(id) {
+                drop(web_connections);
+                warn!("Web connection already exists: {}", id);
+                let _ = ws_stream.close(None).await;
+                return;
+            }
 
-                                        connection.ws_sink.lock().await.send(Message::Text(serde_json::to_string(&MessageType::ICE(ice)).unwrap())).await.unwrap();
+            info!("Web connection established: {}", id);
+            // The stream is only read by the connection task below, the sink is shared with the relay
+            let (ws_sink, mut ws_stream) = ws_stream.split();
+            let ws_sink = Arc::new(Mutex::new(ws_sink));
+            let mut heartbeat = Heartbeat::new(&heartbeat);
+
+            web_connections.insert(id.to_string(), WebConnection {
+                ws_sink: ws_sink.clone(),
+                session: PLMutex::new(None),
+                peer: PLRwLock::new(None),
+            });
+            drop(web_connections);
+            METRICS.connected(Peer::Web);
+            let events = relay.events.clone();
+            events.emit(&window, ServerEvent::WebAdded(id.to_string()));
+            relay.reattach(id).await;
+            let requests = relay.requests.clone();
+            let web_connections = relay.web_connections.clone();
+            let discord_connections = relay.discord_connections.clone();
+            tauri::async_runtime::spawn({
+                let id = id.to_string();
+                async move {
+                    loop {
+                        match read_message(&mut ws_stream, &ws_sink, &mut heartbeat, &id).await {
+                            Status::Ok(signal) => {
+                                if !matches!(signal.message, MessageType::Hello(_)) {
+                                    if let Some(connection) = web_connections.read().await.get(&id) {
+                                        check_legacy(&id, &connection.peer, &events, &window);
                                     }
-                                    MessageType::Offer(offer) => {
-                                        info!("Offer: {:?}", offer);
+                                }
 
-                                        let web_connections = web_connections.read().await;
+                                let request_id = signal.request_id;
+                                let result = match signal.message {
+                                    MessageType::Hello(hello) => {
+                                        if let Some(connection) = web_connections.read().await.get(&id) {
+                                            handle_hello(hello, ClientKind::Web, &id, &connection.peer, &connection.ws_sink, &events, &window).await;
+                                        }
+                                        continue;
+                                    }
+                                    MessageType::Answer(answer) => {
+                                        info!("Answer: {:?}", answer);
 
-                                        let connection = web_connections.values().find(|connection| {
-                                            connection.linked_stream.read().is_some() && connection.linked_stream.read().as_ref().unwrap() == offer.stream_id.as_ref().expect("Offer stream id is none on offer from discord")
-                                        }).expect("No web connection found for offer from discord");
+                                        let result = forward_to_discord(&web_connections, &discord_connections, &requests, &id, MessageType::Answer(answer)).await;
+                                        if result.is_ok() {
+                                            METRICS.answers_to_discord.inc();
+                                        }
+                                        result
+                                    }
+                                    MessageType::ICE(ice) => {
+                                        info!("ICE: {:?}", ice);
 
-                                        connection.ws_sink.lock().await.send(Message::Text(serde_json::to_string(&MessageType::Offer(offer)).unwrap())).await.unwrap();
+                                        let result = forward_to_discord(&web_connections, &discord_connections, &requests, &id, MessageType::ICE(ice)).await;
+                                        if result.is_ok() {
+                                            METRICS.ice_to_discord.inc();
+                                        }
+                                        result
+                                    }
+                                    MessageType::Ack(ack) => {
+                                        handle_ack(&requests, &id, ack);
+                                        continue;
                                     }
-                                    _ => {
-                                        error!("Invalid signal from discord: {:?}", event);
+                                    MessageType::Error(error) => {
+                                        handle_error(&requests, &id, &error);
+                                        continue;
                                     }
+                                    event => {
+                                        error!("Invalid signal from web: {:?}", event);
+                                        Err(ErrorEvent::new(ErrorCode::UnexpectedMessage, format!("Targets aren't expected to send {}", event.name())))
+                                    }
+                                };
+
+                                if let Some(connection) = web_connections.read().await.get(&id) {
+                                    reply(&connection.ws_sink, request_id, result).await;
                                 }
                             }
                             Status::Unhandled(msg) => {
-                                warn!("Unhandled message from discord: {:?}", msg);
+                                warn!("Unhandled message from web: {:?}", msg);
+                                METRICS.unhandled(Peer::Web);
+                                if let Some(connection) = web_connections.read().await.get(&id) {
+                                    let (request_id, error) = invalid_message(&msg);
+                                    reply(&connection.ws_sink, request_id, Err(error)).await;
+                                }
                             }
                             Status::Closed => {
-                                info!("Discord connection closed");
-                                discord_connection.write().await.take();
-                                //Removing all discord streams
-                                discord_streams.write().await.clear();
-                                window.emit("discord-disconnected", ()).unwrap();
+                                info!("Web connection closed: {}", id);
+                                METRICS.disconnected(Peer::Web);
+                                // The target lets go of its stream until it comes back, the capture ends if it was the last one watching
+                                let session = web_connections.write().await.remove(&id).and_then(|connection| connection.session.lock().take());
+                                if let Some(session) = session {
+                                    relay.detach(&id, session).await;
+                                }
+                                events.emit(&window, ServerEvent::WebRemoved(id));
                                 break;
                             }
                         }
-                    };
-                });
-            } else {
-                let id = uri.split('/').last().unwrap_or_default();
-                if id.is_empty() {
-                    warn!("Invalid web connection request: {:?}", uri);
-                    let _ = ws_stream.close(None).await;
-                    continue;
-                }
-                if self.web_connections.read().await.contains_key(id) {
-                    warn!("Web connection already exists: {}", id);
-                    let _ = ws_stream.close(None).await;
-                    continue;
+                    }
                 }
+            });
+        }
+    }
+}
 
-                info!("Web connection established: {}", id);
-                let (ws_sink, ws_stream) = ws_stream.split();
+/// A WebSocket connection whose handshake was already done by the web server
+pub struct Upgrade {
+    pub ws_stream: WebSocketStream<MaybeTlsStream>,
+    pub uri: String,
+}
 
-                self.web_connections.write().await.insert(id.to_string(), WebConnection {
-                    ws_sink: Arc::new(Mutex::new(ws_sink)),
-                    ws_stream: Arc::new(Mutex::new(ws_stream)),
-                    linked_stream: Arc::new(PLRwLock::new(None)),
-                });
-                let connection = self.web_connections.read().await.get(id).unwrap().ws_stream.clone();
-                let window = self.window.clone().unwrap();
-                window.emit("web-added", id).unwrap();
-                let web_connections = self.web_connections.clone();
-                let discord_connection = self.discord_connection.clone();
-                tauri::async_runtime::spawn({
-                    let id = id.to_string();
-                    async move {
-                        loop {
-                            let mut connection = connection.lock().await;
-                            let msg = connection.next().await;
-                            let status = handle_message(msg.unwrap().unwrap_or_else(|e| {
-                                error!("Error reading message: {}", e);
-                                Message::Close(None)
-                            }));
-                            match status {
-                                Status::Ok(event) => {
-                                    match event {
-                                        MessageType::Answer(mut answer) => {
-                                            info!("Answer: {:?}", answer);
-
-                                            let target_stream_id = web_connections.read().await.get(&id).unwrap().linked_stream.read().as_ref().unwrap().clone();
-
-                                            let _ = answer.stream_id.insert(target_stream_id.clone());
-
-                                            discord_connection.read().await.as_ref().unwrap().ws_sink.lock().await.send(Message::Text(serde_json::to_string(&MessageType::Answer(answer)).unwrap())).await.unwrap();
-                                        }
-                                        MessageType::ICE(mut ice) => {
-                                            info!("ICE: {:?}", ice);
+enum Incoming {
+    Accepted(std::io::Result<(TcpStream, SocketAddr)>),
+    Upgraded(Option<Upgrade>),
+}
 
-                                            let target_stream_id = web_connections.read().await.get(&id).unwrap().linked_stream.read().as_ref().unwrap().clone();
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
 
-                                            let _ = ice.stream_id.insert(target_stream_id);
+async fn recv_upgrade(upgrades: &mut Option<mpsc::Receiver<Upgrade>>) -> Option<Upgrade> {
+    match upgrades {
+        Some(upgrades) => upgrades.recv().await,
+        None => std::future::pending().await,
+    }
+}
 
-                                            discord_connection.read().await.as_ref().unwrap().ws_sink.lock().await.send(Message::Text(serde_json::to_string(&MessageType::ICE(ice)).unwrap())).await.unwrap();
-                                        }
-                                        _ => {
-                                            error!("Invalid signal from web: {:?}", event);
-                                        }
-                                    }
-                                }
-                                Status::Unhandled(msg) => {
-                                    warn!("Unhandled message from web: {:?}", msg);
-                                }
-                                Status::Closed => {
-                                    info!("Web connection closed: {}", id);
-                                    web_connections.write().await.remove(&id);
-                                    window.emit("web-removed", id).unwrap();
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                });
+/// Answers the hello with the agreed version and capabilities, a refused peer gets a close frame with the reason
+/// and is then cleaned up by its read loop like any other closed connection
+async fn handle_hello<R: tauri::Runtime>(hello: HelloEvent, expected: ClientKind, peer_name: &str, peer: &PLRwLock<Option<PeerInfo>>, ws_sink: &WsSink, events: &EventBus, window: &tauri::Window<R>) {
+    match protocol::negotiate(&hello, expected) {
+        Ok(info) => {
+            info!("Hello from {}: {:?}", peer_name, hello);
+            if info.protocol_version != hello.protocol_version {
+                warn!("{} speaks protocol version {}, downgraded to {}", peer_name, hello.protocol_version, info.protocol_version);
+                events.emit(window, ServerEvent::ProtocolMismatch(ProtocolMismatchEvent {
+                    peer: peer_name.to_string(),
+                    client_version: Some(hello.version.clone()),
+                    protocol_version: hello.protocol_version,
+                    server_protocol_version: PROTOCOL_VERSION,
+                    refused: false,
+                    reason: format!("Downgraded to protocol version {}", info.protocol_version),
+                }));
+            }
+
+            let _ = ws_sink.lock().await.send(Message::Text(serde_json::to_string(&MessageType::Hello(info.server_hello())).unwrap())).await;
+            *peer.write() = Some(info);
+        }
+        Err(reason) => {
+            warn!("Refused {}: {}", peer_name, reason);
+            events.emit(window, ServerEvent::ProtocolMismatch(ProtocolMismatchEvent {
+                peer: peer_name.to_string(),
+                client_version: Some(hello.version),
+                protocol_version: hello.protocol_version,
+                server_protocol_version: PROTOCOL_VERSION,
+                refused: true,
+                reason: reason.clone(),
+            }));
+
+            let _ = ws_sink.lock().await.send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Protocol,
+                reason: reason.into(),
+            }))).await;
+        }
+    }
+}
+
+/// Peers sending anything before a hello predate the handshake, they keep working as protocol version 0
+fn check_legacy<R: tauri::Runtime>(peer_name: &str, peer: &PLRwLock<Option<PeerInfo>>, events: &EventBus, window: &tauri::Window<R>) {
+    {
+        let mut peer = peer.write();
+        if peer.is_some() {
+            return;
+        }
+        *peer = Some(PeerInfo::legacy());
+    }
+
+    warn!("{} didn't say hello, treating it as a legacy client", peer_name);
+    events.emit(window, ServerEvent::ProtocolMismatch(ProtocolMismatchEvent {
+        peer: peer_name.to_string(),
+        client_version: None,
+        protocol_version: 0,
+        server_protocol_version: PROTOCOL_VERSION,
+        refused: false,
+        reason: "The client predates the protocol handshake, update it".to_string(),
+    }));
+}
+
+/// Sends the message, numbered and tracked when the peer negotiated request ids and is expected to answer it
+async fn send_signal(ws_sink: &WsSink, peer: &PLRwLock<Option<PeerInfo>>, requests: &Requests, message: MessageType) -> Result<(), Error> {
+    let numbered = message.expects_reply() && peer.read().as_ref().map_or(false, |peer| peer.supports(REQUEST_IDS));
+
+    let mut signal = Signal::new(message);
+    if numbered {
+        signal.request_id = Some(requests.register(Pending {
+            message: signal.message.name(),
+            stream_id: signal.message.stream_id().map(str::to_string),
+            session_id: signal.message.session_id().map(str::to_string),
+        }));
+    }
+
+    ws_sink.lock().await.send(Message::Text(serde_json::to_string(&signal).unwrap())).await
+}
+
+/// Answers a message from a peer, acks are only sent for messages carrying a request id while errors always are
+async fn reply(ws_sink: &WsSink, request_id: Option<u64>, result: Result<(), ErrorEvent>) {
+    let message = match result {
+        Ok(()) => match request_id {
+            Some(request_id) => MessageType::Ack(AckEvent { request_id }),
+            None => return,
+        },
+        Err(error) => {
+            warn!("Replying with error: {:?} {}", error.code, error.message);
+            MessageType::Error(ErrorEvent {
+                request_id,
+                ..error
+            })
+        }
+    };
+
+    let _ = ws_sink.lock().await.send(Message::Text(serde_json::to_string(&Signal::new(message)).unwrap())).await;
+}
+
+/// Sends a signal from discord to the target whose session it belongs to, candidates ahead of the offer are held back
+/// by the session, the ones for a session that ended are dropped
+async fn forward_to_target(web_connections: &WebConnections, requests: &Requests, message: MessageType) -> Result<(), ErrorEvent> {
+    let Some(stream_id) = message.stream_id().map(str::to_string) else {
+        return Err(ErrorEvent::new(ErrorCode::InvalidMessage, format!("{} from discord without a stream id", message.name())));
+    };
+
+    let session_id = message.session_id().map(str::to_string);
+
+    let web_connections = web_connections.read().await;
+    let routed = web_connections.values().find_map(|connection| {
+        let mut session = connection.session.lock();
+        let session = session.as_mut().filter(|session| session.matches(&stream_id, session_id.as_deref()))?;
+        Some((connection, session.from_discord(message.clone())))
+    });
+    let Some((connection, messages)) = routed else {
+        warn!("Dropped {} for stream {}, no target is linked to it", message.name(), stream_id);
+        return Err(ErrorEvent::new(ErrorCode::NoTargetForStream, format!("No target is linked to stream {}", stream_id)));
+    };
+
+    for message in messages {
+        send_signal(&connection.ws_sink, &connection.peer, requests, message).await
+            .map_err(|e| ErrorEvent::new(ErrorCode::NoTargetForStream, format!("Failed to reach the target linked to stream {}: {}", stream_id, e)))?;
+    }
+    Ok(())
+}
+
+/// Sends a signal from a target to discord, tagged with the stream the target is linked to and its session.
+/// Candidates ahead of the answer are held back by the session, the ones of an unlinked target are dropped
+async fn forward_to_discord(web_connections: &WebConnections, discord_connections: &DiscordConnections, requests: &Requests, target: &str, mut message: MessageType) -> Result<(), ErrorEvent> {
+    let messages = {
+        let web_connections = web_connections.read().await;
+        let mut session = web_connections.get(target).map(|connection| connection.session.lock());
+        match session.as_mut().and_then(|session| session.as_mut()) {
+            Some(session) => {
+                message.set_stream_id(session.stream_id.clone());
+                message.set_session_id(session.id.clone());
+                session.from_target(message)
+            }
+            None => {
+                warn!("Dropped {} from target {}, it isn't linked to any stream", message.name(), target);
+                return Err(ErrorEvent::new(ErrorCode::TargetNotLinked, format!("Target {} isn't linked to any stream", target)));
             }
         }
+    };
+
+    for message in messages {
+        send_to_client(discord_connections, requests, message).await
+            .map_err(|e| ErrorEvent::new(ErrorCode::DiscordDisconnected, e.to_string()))?;
     }
+    Ok(())
 }
 
+/// Sends a signal to the discord client its stream comes from, with the stream id that client knows
+async fn send_to_client(discord_connections: &DiscordConnections, requests: &Requests, mut message: MessageType) -> Result<(), RelayError> {
+    let (client_id, stream_id) = message.stream_id()
+        .and_then(clients::split)
+        .map(|(client_id, stream_id)| (client_id.to_string(), stream_id.to_string()))
+        .ok_or(RelayError::StreamNotFound)?;
+    message.set_stream_id(stream_id);
+
+    let discord_connections = discord_connections.read().await;
+    let connection = discord_connections.get(&client_id).ok_or(RelayError::DiscordDisconnected)?;
+    send_signal(&connection.ws_sink, &connection.peer, requests, message).await
+        .map_err(|_| RelayError::DiscordDisconnected)
+}
+
+fn handle_ack(requests: &Requests, peer_name: &str, ack: AckEvent) {
+    match requests.resolve(ack.request_id) {
+        Some(pending) => info!("{} acknowledged {} {}", peer_name, pending.message, ack.request_id),
+        None => warn!("{} acknowledged unknown request {}", peer_name, ack.request_id),
+    }
+}
+
+/// Logs the error reported by a peer, returns the failed request
+fn handle_error(requests: &Requests, peer_name: &str, error: &ErrorEvent) -> Option<Pending> {
+    let pending = error.request_id.and_then(|request_id| requests.resolve(request_id));
+    match &pending {
+        Some(pending) => warn!("{} failed {} for stream {:?}: {:?} {}", peer_name, pending.message, pending.stream_id, error.code, error.message),
+        None => warn!("{} reported an error: {:?} {}", peer_name, error.code, error.message),
+    }
+    pending
+}
+
+/// The error sent back for a message that couldn't be parsed, with its request id when it could still be read
+fn invalid_message(message: &Message) -> (Option<u64>, ErrorEvent) {
+    let request_id = message.to_text().ok()
+        .and_then(|text| serde_json::from_str::<serde_json::Value>(text).ok())
+        .and_then(|value| value.get("requestId").and_then(serde_json::Value::as_u64));
+
+    (request_id, ErrorEvent::new(ErrorCode::InvalidMessage, "Unknown message type or invalid fields"))
+}
+
+/// Reads the next message, pinging the peer while it's quiet. A peer silent past the idle timeout
+/// is reported as closed so it goes through the same cleanup as a closed connection
+async fn read_message(ws_stream: &mut SplitStream<WebSocketStream<MaybeTlsStream>>, ws_sink: &WsSink, heartbeat: &mut Heartbeat, peer_name: &str) -> Status {
+    loop {
+        tokio::select! {
+            msg = ws_stream.next() => {
+                heartbeat.seen();
+                match msg {
+                    None => return Status::Closed,
+                    Some(Err(e)) => {
+                        error!("Error reading message: {}", e);
+                        return Status::Closed;
+                    }
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                    Some(Ok(msg)) => return handle_message(msg),
+                }
+            }
+            _ = heartbeat.tick() => {
+                if heartbeat.timed_out() {
+                    warn!("{} stopped answering pings, dropping the connection", peer_name);
+                    return Status::Closed;
+                }
+                if ws_sink.lock().await.send(Message::Ping(Vec::new())).await.is_err() {
+                    return Status::Closed;
+                }
+            }
+        }
+    }
+}
 
 fn handle_message(message: Message) -> Status {
     if message.is_close() {
         return Status::Closed;
     } else if let Ok(text) = message.to_text() {
-        if let Ok(event) = serde_json::from_str::<MessageType>(text) {
-            return Status::Ok(event);
+        if let Ok(signal) = serde_json::from_str::<Signal>(text) {
+            return Status::Ok(signal);
         }
     }
 
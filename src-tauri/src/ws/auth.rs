@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use http::{header, HeaderMap, Method, StatusCode, Uri};
+use tracing::warn;
+
+use crate::ws::origins::OriginPolicy;
+
+/// Query parameter carrying the token on the WS URLs, browsers can't set headers on a WebSocket
+const TOKEN_PARAMETER: &str = "token";
+
+/// Tokens of the target sockets, none are required by default so the browser sources already set up keep working, and of the HTTP API
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Token required from the targets that don't have one of their own
+    pub target_token: Option<String>,
+    /// Tokens required from specific targets, keyed by target id
+    pub target_tokens: HashMap<String, String>,
+    /// Token the HTTP API requests changing anything send as `Authorization: Bearer <token>`, generated when missing
+    pub api_token: Option<String>,
+}
+
+/// Checks the origin and the token of a WS handshake, before the connection is registered, and the HTTP API requests
+#[derive(Clone)]
+pub struct Auth {
+    /// Per-install secret from the plugin settings, required on `/discord`
+    discord_secret: String,
+    config: AuthConfig,
+    origins: OriginPolicy,
+}
+
+impl Auth {
+    pub fn new(discord_secret: String, config: AuthConfig, origins: OriginPolicy) -> Self {
+        Self {
+            discord_secret,
+            config,
+            origins,
+        }
+    }
+
+    /// The origin must be allowed, then `/discord` needs the discord secret and any other path is a target named by its last segment
+    pub fn authorize(&self, uri: &Uri, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let path = uri.path();
+
+        let origin = headers.get(header::ORIGIN).map(|origin| origin.to_str().unwrap_or_default());
+        if let Err(reason) = self.origins.check(origin) {
+            warn!("Refused WS connection on {}, {}", path, reason);
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let (expected, peer) = if path == "/discord" {
+            (Some(self.discord_secret.as_str()), "discord")
+        } else {
+            let target = path.rsplit('/').next().unwrap_or_default();
+            let expected = self.config.target_tokens.get(target).or(self.config.target_token.as_ref());
+            (expected.map(String::as_str), target)
+        };
+        let Some(expected) = expected else {
+            return Ok(());
+        };
+
+        let token = uri.query().and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == TOKEN_PARAMETER)
+                .map(|(_, value)| value.into_owned())
+        });
+        match token {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+            Some(_) => {
+                warn!("Refused WS connection of {}, wrong token", peer);
+                Err(StatusCode::FORBIDDEN)
+            }
+            None => {
+                warn!("Refused WS connection of {}, no token", peer);
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }
+    }
+
+    /// The `Host` of the HTTP API requests must be one of the server's own names and the `Origin`, sent by the browsers, an allowed one.
+    /// Requests with any other method than GET, HEAD and OPTIONS also need the API token, a page can send them without CORS
+    pub fn authorize_api(&self, method: &Method, uri: &Uri, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let path = uri.path();
+
+        let host = headers.get(header::HOST).map(|host| host.to_str().unwrap_or_default());
+        let origin = headers.get(header::ORIGIN).map(|origin| origin.to_str().unwrap_or_default());
+        if let Err(reason) = self.origins.check_host(host).and_then(|_| self.origins.check(origin)) {
+            warn!("Refused HTTP request on {}, {}", path, reason);
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            return Ok(());
+        }
+        let Some(expected) = &self.config.api_token else {
+            return Ok(());
+        };
+
+        let token = headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if constant_time_eq(token.trim().as_bytes(), expected.as_bytes()) => Ok(()),
+            Some(_) => {
+                warn!("Refused HTTP request on {}, wrong API token", path);
+                Err(StatusCode::FORBIDDEN)
+            }
+            None => {
+                warn!("Refused HTTP request on {}, no API token", path);
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }
+    }
+}
+
+/// Generates a per-install secret
+pub fn generate_secret() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use http::HeaderValue;
+
+    use super::*;
+    use crate::ws::origins::OriginConfig;
+
+    fn auth() -> Auth {
+        let origins = OriginPolicy::new(&OriginConfig::default(), 4651, IpAddr::V4(Ipv4Addr::LOCALHOST), &[]);
+        let config = AuthConfig {
+            api_token: Some("secret".to_string()),
+            ..AuthConfig::default()
+        };
+        Auth::new("discord".to_string(), config, origins)
+    }
+
+    fn headers(entries: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("localhost:4651"));
+        for (name, value) in entries {
+            headers.insert(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn api_reads_need_no_token() {
+        let uri = Uri::from_static("/api/targets");
+        assert_eq!(auth().authorize_api(&Method::GET, &uri, &headers(&[])), Ok(()));
+    }
+
+    #[test]
+    fn api_changes_need_the_token() {
+        let auth = auth();
+        let uri = Uri::from_static("/api/targets/main/unlink");
+        assert_eq!(auth.authorize_api(&Method::POST, &uri, &headers(&[])), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(auth.authorize_api(&Method::POST, &uri, &headers(&[(header::AUTHORIZATION, "Bearer wrong")])), Err(StatusCode::FORBIDDEN));
+        assert_eq!(auth.authorize_api(&Method::POST, &uri, &headers(&[(header::AUTHORIZATION, "Bearer secret")])), Ok(()));
+    }
+
+    #[test]
+    fn api_refuses_foreign_origins() {
+        let auth = auth();
+        let uri = Uri::from_static("/api/targets/main/unlink");
+        let foreign = headers(&[(header::AUTHORIZATION, "Bearer secret"), (header::ORIGIN, "https://evil.example")]);
+        assert_eq!(auth.authorize_api(&Method::POST, &uri, &foreign), Err(StatusCode::FORBIDDEN));
+        let own = headers(&[(header::AUTHORIZATION, "Bearer secret"), (header::ORIGIN, "http://localhost:4651")]);
+        assert_eq!(auth.authorize_api(&Method::POST, &uri, &own), Ok(()));
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex as PLMutex;
+
+/// Number of targets watching each stream. Discord keeps capturing a stream until the last of them lets go,
+/// each target only opens and closes its own peer connection
+#[derive(Clone)]
+pub struct Captures {
+    counts: Arc<PLMutex<HashMap<String, usize>>>,
+}
+
+impl Captures {
+    pub fn new() -> Self {
+        Self {
+            counts: Arc::new(PLMutex::new(HashMap::new())),
+        }
+    }
+
+    /// Counts one more target on the stream, returns whether it's the first one
+    pub fn acquire(&self, stream_id: &str) -> bool {
+        let mut counts = self.counts.lock();
+        let count = counts.entry(stream_id.to_string()).or_insert(0);
+        *count += 1;
+        *count == 1
+    }
+
+    /// Counts one target less on the stream, returns whether it was the last one
+    pub fn release(&self, stream_id: &str) -> bool {
+        let mut counts = self.counts.lock();
+        match counts.get_mut(stream_id) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            _ => {
+                counts.remove(stream_id);
+                true
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use crate::ws::message::MessageType;
+
+/// Client id of the plugins that don't send one in their hello, or that predate the hello
+pub const DEFAULT_CLIENT_ID: &str = "discord";
+
+/// Separates the client id from the id the client gave the stream, client ids never contain it
+const SEPARATOR: char = ':';
+
+/// The id a discord client is known by, anything but letters, digits, `-`, `_` and `.` is replaced
+pub fn client_id(requested: Option<&str>) -> String {
+    match requested.map(str::trim).filter(|requested| !requested.is_empty()) {
+        Some(requested) => requested.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '-' })
+            .collect(),
+        None => DEFAULT_CLIENT_ID.to_string(),
+    }
+}
+
+/// Name of the client in the logs and the protocol mismatch events
+pub fn peer_name(client_id: &str) -> String {
+    if client_id == DEFAULT_CLIENT_ID {
+        DEFAULT_CLIENT_ID.to_string()
+    } else {
+        qualify(DEFAULT_CLIENT_ID, client_id)
+    }
+}
+
+/// Streams of different clients can share ids, the relay refers to them prefixed with the client they come from
+pub fn qualify(client_id: &str, stream_id: &str) -> String {
+    format!("{}{}{}", client_id, SEPARATOR, stream_id)
+}
+
+/// Splits a stream id used by the relay into the client id and the id the client knows the stream by
+pub fn split(stream_id: &str) -> Option<(&str, &str)> {
+    stream_id.split_once(SEPARATOR)
+}
+
+/// Prefixes the stream id of a signal received from the client
+pub fn qualify_message(client_id: &str, message: &mut MessageType) {
+    if let Some(stream_id) = message.stream_id().map(|stream_id| qualify(client_id, stream_id)) {
+        message.set_stream_id(stream_id);
+    }
+}
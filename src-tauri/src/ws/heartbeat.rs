@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// Pings sent to every WS peer and how long a silent one is kept. Browsers and discord answer pings on their own,
+/// so a peer that stays silent past the timeout is gone even if its TCP connection isn't closed yet
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// Seconds between two pings, 0 disables the pings and the timeout
+    pub ping_interval: u64,
+    /// Seconds without any message or pong after which the peer is dropped, at least two ping intervals
+    pub idle_timeout: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: 15,
+            idle_timeout: 45,
+        }
+    }
+}
+
+/// Liveness of one connection, fed with every message read from it
+pub struct Heartbeat {
+    interval: Option<Interval>,
+    idle_timeout: Duration,
+    last_seen: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(config: &HeartbeatConfig) -> Self {
+        let interval = (config.ping_interval > 0).then(|| {
+            let period = Duration::from_secs(config.ping_interval);
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+
+        Self {
+            interval,
+            idle_timeout: Duration::from_secs(config.idle_timeout.max(config.ping_interval * 2)),
+            last_seen: Instant::now(),
+        }
+    }
+
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Waits until the next ping is due, forever when heartbeats are disabled
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.interval.is_some() && self.last_seen.elapsed() >= self.idle_timeout
+    }
+}
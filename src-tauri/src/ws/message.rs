@@ -4,6 +4,8 @@ use ts_rs::TS;
 #[ts(export)]
 #[serde(tag = "type", content = "detail")]
 pub enum MessageType {
+    #[serde(rename = "hello")]
+    Hello(HelloEvent),
     #[serde(rename = "remove")]
     Remove(Vec<RemoveStreamEvent>),
     #[serde(rename = "ice")]
@@ -16,10 +18,172 @@ pub enum MessageType {
     Capture(CaptureEvent),
     #[serde(rename = "endCapture")]
     EndCapture(CaptureEvent),
+    /// A target stopped watching a stream that other targets still watch, only its peer connection is closed
+    #[serde(rename = "endSession")]
+    EndSession(CaptureEvent),
     #[serde(rename = "unlink")]
     Unlink,
     #[serde(rename = "updateUserInfo")]
-    UpdateUserInfo(Vec<UpdateUserInfoEvent>)
+    UpdateUserInfo(Vec<UpdateUserInfoEvent>),
+    #[serde(rename = "ack")]
+    Ack(AckEvent),
+    #[serde(rename = "error")]
+    Error(ErrorEvent),
+}
+
+impl MessageType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::Hello(_) => "hello",
+            MessageType::Remove(_) => "remove",
+            MessageType::ICE(_) => "ice",
+            MessageType::Answer(_) => "answer",
+            MessageType::Offer(_) => "offer",
+            MessageType::Capture(_) => "capture",
+            MessageType::EndCapture(_) => "endCapture",
+            MessageType::EndSession(_) => "endSession",
+            MessageType::Unlink => "unlink",
+            MessageType::UpdateUserInfo(_) => "updateUserInfo",
+            MessageType::Ack(_) => "ack",
+            MessageType::Error(_) => "error",
+        }
+    }
+
+    /// Whether the receiver answers it with an `ack` or an `error` when it carries a request id
+    pub fn expects_reply(&self) -> bool {
+        !matches!(self, MessageType::Hello(_) | MessageType::Ack(_) | MessageType::Error(_))
+    }
+
+    /// The stream the signal is about, if any
+    pub fn stream_id(&self) -> Option<&str> {
+        match self {
+            MessageType::ICE(ICEEvent { stream_id, .. }) | MessageType::Answer(AnswerOfferEvent { stream_id, .. }) | MessageType::Offer(AnswerOfferEvent { stream_id, .. }) => stream_id.as_deref(),
+            MessageType::Capture(capture) | MessageType::EndCapture(capture) | MessageType::EndSession(capture) => Some(&capture.stream_id),
+            _ => None,
+        }
+    }
+
+    /// The session of the target the signal is about, missing on signals from plugins that predate sessions
+    pub fn session_id(&self) -> Option<&str> {
+        match self {
+            MessageType::ICE(ICEEvent { session_id, .. }) | MessageType::Answer(AnswerOfferEvent { session_id, .. }) | MessageType::Offer(AnswerOfferEvent { session_id, .. }) => session_id.as_deref(),
+            MessageType::Capture(capture) | MessageType::EndCapture(capture) | MessageType::EndSession(capture) => capture.session_id.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Sets the stream the signal is about, the one a target is linked to or the qualified id of a discord stream
+    pub fn set_stream_id(&mut self, stream_id: String) {
+        match self {
+            MessageType::ICE(ICEEvent { stream_id: id, .. }) | MessageType::Answer(AnswerOfferEvent { stream_id: id, .. }) | MessageType::Offer(AnswerOfferEvent { stream_id: id, .. }) => *id = Some(stream_id),
+            MessageType::Capture(capture) | MessageType::EndCapture(capture) | MessageType::EndSession(capture) => capture.stream_id = stream_id,
+            _ => {}
+        }
+    }
+
+    /// Tags a signal from a target with its session
+    pub fn set_session_id(&mut self, session_id: String) {
+        match self {
+            MessageType::ICE(ICEEvent { session_id: id, .. }) | MessageType::Answer(AnswerOfferEvent { session_id: id, .. }) | MessageType::Offer(AnswerOfferEvent { session_id: id, .. }) => *id = Some(session_id),
+            MessageType::Capture(capture) | MessageType::EndCapture(capture) | MessageType::EndSession(capture) => capture.session_id = Some(session_id),
+            _ => {}
+        }
+    }
+}
+
+/// A message on the wire, the request id is set on messages to peers that negotiated the `requestIds` capability,
+/// and on the messages they send when they want an `ack` or an `error` back
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct Signal {
+    #[serde(rename = "requestId", default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u64>,
+    #[serde(flatten)]
+    pub message: MessageType,
+}
+
+impl Signal {
+    pub fn new(message: MessageType) -> Self {
+        Self {
+            request_id: None,
+            message,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone)]
+#[ts(export)]
+pub struct AckEvent {
+    #[serde(rename = "requestId")]
+    pub request_id: u64,
+}
+
+/// The request couldn't be handled, `request_id` is missing when the failed message didn't carry one
+#[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone)]
+#[ts(export)]
+pub struct ErrorEvent {
+    #[serde(rename = "requestId")]
+    #[ts(optional)]
+    pub request_id: Option<u64>,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorEvent {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            request_id: None,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// Not valid JSON, an unknown type or a missing field
+    InvalidMessage,
+    /// A known message this peer isn't supposed to send
+    UnexpectedMessage,
+    /// A target sent an answer or a candidate while not linked to any stream
+    TargetNotLinked,
+    /// Discord sent an offer or a candidate for a stream no target is linked to
+    NoTargetForStream,
+    /// A capture was requested for a stream the plugin doesn't know
+    StreamNotFound,
+    DiscordDisconnected,
+    /// The WebRTC negotiation failed on the peer
+    NegotiationFailed,
+}
+
+/// First message sent by each side, see `ws::protocol` for how versions and capabilities are agreed on
+#[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone)]
+#[ts(export)]
+pub struct HelloEvent {
+    #[serde(rename = "protocolVersion")]
+    pub protocol_version: u32,
+    /// Oldest protocol version the sender can fall back to, defaults to `protocol_version`
+    #[serde(rename = "minProtocolVersion")]
+    #[ts(optional)]
+    pub min_protocol_version: Option<u32>,
+    pub client: ClientKind,
+    /// Tells apart the discord clients connected at the same time (e.g. Stable and Canary logged into different accounts)
+    #[serde(rename = "clientId")]
+    #[ts(optional)]
+    pub client_id: Option<String>,
+    pub version: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKind {
+    Discord,
+    Web,
+    Server,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone)]
@@ -39,7 +203,20 @@ pub struct UpdateUserInfoEvent {
     #[serde(rename = "userId")]
     pub user_id: String,
     #[serde(rename = "info")]
-    pub info: UserInfo
+    pub info: UserInfo,
+    /// Missing from the plugins that predate it
+    #[serde(default)]
+    #[ts(optional)]
+    pub kind: Option<StreamKind>,
+}
+
+/// Whether the stream is a camera or a screen share (Go Live)
+#[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamKind {
+    Camera,
+    Screen,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone)]
@@ -49,29 +226,42 @@ pub struct RemoveStreamEvent {
     pub stream_id: String
 }
 
-/// stream_id is optional since it's present only if the event is from discord
+/// stream_id is optional since it's present only if the event is from discord,
+/// session_id is set by the relay and by plugins supporting the `sessions` capability
 #[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone)]
 #[ts(export)]
 pub struct ICEEvent {
     #[serde(rename = "streamId")]
     #[ts(optional)]
     pub stream_id: Option<String>,
+    #[serde(rename = "sessionId")]
+    #[ts(optional)]
+    pub session_id: Option<String>,
     pub candidate: String,
 }
 
-/// stream_id is optional since it's present only if the event is from discord
+/// stream_id is optional since it's present only if the event is from discord,
+/// session_id is set by the relay and by plugins supporting the `sessions` capability
 #[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone)]
 #[ts(export)]
 pub struct AnswerOfferEvent {
     #[serde(rename = "streamId")]
     #[ts(optional)]
     pub stream_id: Option<String>,
+    #[serde(rename = "sessionId")]
+    #[ts(optional)]
+    pub session_id: Option<String>,
     pub sdp: String,
 }
 
+/// session_id identifies the target's peer connection, missing for plugins that predate sessions
+/// and on the `endCapture` sent once the last target let go of the stream
 #[derive(serde::Deserialize, serde::Serialize, Debug, TS, Clone)]
 #[ts(export)]
 pub struct CaptureEvent {
     #[serde(rename = "streamId")]
     pub stream_id: String,
+    #[serde(rename = "sessionId")]
+    #[ts(optional)]
+    pub session_id: Option<String>,
 }
\ No newline at end of file
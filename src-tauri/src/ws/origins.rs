@@ -0,0 +1,180 @@
+use std::net::IpAddr;
+
+use url::{Host, Url};
+
+/// Origins of the discord clients, and of the pages OBS browser sources load from local files
+const DEFAULT_ORIGINS: &[&str] = &[
+    "https://discord.com",
+    "https://canary.discord.com",
+    "https://ptb.discord.com",
+    "http://absolute",
+];
+
+/// Origins allowed to open a WS connection besides the defaults, which are the discord clients,
+/// OBS local files and the pages of the web server itself
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct OriginConfig {
+    /// Full origins like `https://overlay.example.com:8443`, `*` allows any
+    pub allowed: Vec<String>,
+    /// Names and addresses the web server is reached by from other machines, like `192.168.1.10` when it listens on `0.0.0.0`.
+    /// The names of tls.hostnames are accepted too
+    pub hostnames: Vec<String>,
+}
+
+/// Checks the `Origin` header of the WS handshakes, so the websites open in the user's browser can't connect.
+/// Handshakes without one come from other programs, which can send any header anyway
+#[derive(Clone)]
+pub struct OriginPolicy {
+    allowed: Vec<String>,
+    web_port: u16,
+    /// Address the servers listen on, `None` when they listen on every interface
+    listen_address: Option<IpAddr>,
+    /// Names the web server is reached by besides localhost and the loopback addresses
+    hostnames: Vec<String>,
+}
+
+impl OriginPolicy {
+    pub fn new(config: &OriginConfig, web_port: u16, listen_address: IpAddr, hostnames: &[String]) -> Self {
+        Self {
+            allowed: DEFAULT_ORIGINS.iter().map(|origin| origin.to_string())
+                .chain(config.allowed.iter().map(|origin| normalize(origin)))
+                .collect(),
+            web_port,
+            listen_address: (!listen_address.is_unspecified()).then_some(listen_address),
+            hostnames: config.hostnames.iter().chain(hostnames)
+                .map(|hostname| hostname.trim().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Returns why the origin is refused
+    pub fn check(&self, origin: Option<&str>) -> Result<(), String> {
+        let Some(origin) = origin else {
+            return Ok(());
+        };
+        let normalized = normalize(origin);
+        if self.allowed.iter().any(|allowed| allowed == "*" || allowed == &normalized) || self.is_own_origin(&normalized) {
+            return Ok(());
+        }
+
+        Err(format!("origin {} isn't a discord client, OBS or a page of the web server, add it to origins.allowed to let it connect \
+            or its host to origins.hostnames if it is this machine", origin))
+    }
+
+    /// A page of the web server, reached by localhost, a loopback address, the listen address or one of the configured names.
+    /// Other names and addresses could be anyone's, a website could make its own name resolve to this machine
+    fn is_own_origin(&self, origin: &str) -> bool {
+        let Ok(url) = Url::parse(origin) else {
+            return false;
+        };
+        if !matches!(url.scheme(), "http" | "https") || url.port_or_known_default() != Some(self.web_port) {
+            return false;
+        }
+
+        url.host().map_or(false, |host| self.is_own_host(host))
+    }
+
+    /// Returns why the `Host` of an HTTP request is refused, only the server's own names are accepted so a website
+    /// making its name resolve to this machine can't read the responses. Requests without one don't come from a browser
+    pub fn check_host(&self, host: Option<&str>) -> Result<(), String> {
+        let Some(host) = host else {
+            return Ok(());
+        };
+        let name = match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => name,
+            _ => host,
+        };
+        if Host::parse(name).map_or(false, |parsed| self.is_own_host(parsed)) {
+            return Ok(());
+        }
+
+        Err(format!("host {} isn't localhost, the listen address or a name of origins.hostnames, add it there to reach the server by it", host))
+    }
+
+    fn is_own_host(&self, host: Host<impl AsRef<str>>) -> bool {
+        match host {
+            Host::Ipv4(address) => self.is_own_address(IpAddr::V4(address)),
+            Host::Ipv6(address) => self.is_own_address(IpAddr::V6(address)),
+            Host::Domain(domain) => domain.as_ref() == "localhost" || self.hostnames.iter().any(|hostname| hostname == domain.as_ref()),
+        }
+    }
+
+    /// A loopback address, the listen address or an address among the configured names
+    fn is_own_address(&self, address: IpAddr) -> bool {
+        address.is_loopback()
+            || self.listen_address == Some(address)
+            || self.hostnames.iter().any(|hostname| hostname.parse::<IpAddr>().ok() == Some(address))
+    }
+}
+
+fn normalize(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn policy() -> OriginPolicy {
+        OriginPolicy::new(&OriginConfig::default(), 4651, IpAddr::V4(Ipv4Addr::UNSPECIFIED), &["studio.lan".to_string()])
+    }
+
+    #[test]
+    fn accepts_own_pages() {
+        let policy = policy();
+        assert!(policy.check(Some("http://localhost:4651")).is_ok());
+        assert!(policy.check(Some("http://127.0.0.1:4651")).is_ok());
+        assert!(policy.check(Some("http://[::1]:4651")).is_ok());
+        assert!(policy.check(Some("http://studio.lan:4651")).is_ok());
+    }
+
+    #[test]
+    fn rejects_foreign_origins() {
+        let policy = policy();
+        assert!(policy.check(Some("http://203.0.113.5:4651")).is_err());
+        assert!(policy.check(Some("http://evil.example:4651")).is_err());
+        assert!(policy.check(Some("http://localhost:8080")).is_err());
+    }
+
+    #[test]
+    fn accepts_listen_address() {
+        let policy = OriginPolicy::new(&OriginConfig::default(), 4651, "192.168.1.10".parse().unwrap(), &[]);
+        assert!(policy.check(Some("http://192.168.1.10:4651")).is_ok());
+        assert!(policy.check(Some("http://192.168.1.11:4651")).is_err());
+    }
+
+    #[test]
+    fn accepts_lan_hostnames() {
+        let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        let policy = OriginPolicy::new(&OriginConfig::default(), 4651, unspecified, &[]);
+        assert!(policy.check(Some("http://192.168.1.10:4651")).is_err());
+        assert!(policy.check_host(Some("192.168.1.10:4651")).unwrap_err().contains("origins.hostnames"));
+
+        let config = OriginConfig {
+            hostnames: vec!["192.168.1.10".to_string(), "Studio.lan".to_string()],
+            ..OriginConfig::default()
+        };
+        let policy = OriginPolicy::new(&config, 4651, unspecified, &[]);
+        assert!(policy.check(Some("http://192.168.1.10:4651")).is_ok());
+        assert!(policy.check(Some("http://studio.lan:4651")).is_ok());
+        assert!(policy.check_host(Some("192.168.1.10:4651")).is_ok());
+        assert!(policy.check_host(Some("studio.lan:4651")).is_ok());
+        assert!(policy.check(Some("http://192.168.1.11:4651")).is_err());
+    }
+
+    #[test]
+    fn checks_hosts() {
+        let policy = policy();
+        assert!(policy.check_host(Some("localhost:4651")).is_ok());
+        assert!(policy.check_host(Some("127.0.0.1:4651")).is_ok());
+        assert!(policy.check_host(Some("[::1]:4651")).is_ok());
+        assert!(policy.check_host(Some("[::1]")).is_ok());
+        assert!(policy.check_host(Some("Studio.lan")).is_ok());
+        assert!(policy.check_host(None).is_ok());
+        assert!(policy.check_host(Some("evil.example:4651")).is_err());
+        assert!(policy.check_host(Some("203.0.113.5:4651")).is_err());
+    }
+}
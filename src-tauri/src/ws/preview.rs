@@ -0,0 +1,41 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+/// Latest preview frame of a stream, decoded from the data url sent by the discord plugin
+pub struct StreamPreview {
+    pub content_type: String,
+    pub data: Vec<u8>,
+    pub etag: String,
+}
+
+impl StreamPreview {
+    /// Accepts `data:<mime>;base64,<data>` urls as well as bare base64, in which case the type is sniffed
+    pub fn from_data_url(value: &str) -> Option<Self> {
+        let (content_type, encoded) = match value.strip_prefix("data:").and_then(|value| value.split_once(',')) {
+            Some((header, encoded)) => (header.strip_suffix(";base64").map(str::to_string), encoded),
+            None => (None, value),
+        };
+
+        let data = STANDARD.decode(encoded.trim()).ok()?;
+        if data.is_empty() {
+            return None;
+        }
+
+        let content_type = content_type.filter(|content_type| content_type.starts_with("image/")).unwrap_or_else(|| sniff_image_type(&data).to_string());
+
+        Some(Self {
+            etag: format!("{:x}", md5::compute(&data)),
+            content_type,
+            data,
+        })
+    }
+}
+
+fn sniff_image_type(data: &[u8]) -> &'static str {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
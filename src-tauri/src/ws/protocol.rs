@@ -0,0 +1,66 @@
+use crate::ws::message::{ClientKind, HelloEvent};
+
+/// Version of the signalling protocol spoken by this build, bumped whenever a message changes shape
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest version still understood, advertised in the server hello. 0 stands for the plugins and pages that predate
+/// the hello message, so no peer is too old for now
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+/// Messages carry a `requestId` and are answered with an `ack` or an `error`
+pub const REQUEST_IDS: &str = "requestIds";
+/// Captures carry a `sessionId`, discord runs one peer connection per target watching a stream
+pub const SESSIONS: &str = "sessions";
+
+/// Optional protocol features this build supports, only the ones announced by both ends are used
+pub const CAPABILITIES: &[&str] = &[REQUEST_IDS, SESSIONS];
+
+/// What was agreed on with a peer in the hello exchange
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl PeerInfo {
+    /// A peer that sent a regular message without saying hello first, it's spoken to as version 0
+    pub fn legacy() -> Self {
+        Self {
+            protocol_version: 0,
+            capabilities: Vec::new(),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|supported| supported == capability)
+    }
+
+    /// The hello sent back to the peer with the agreed version and capabilities
+    pub fn server_hello(&self) -> HelloEvent {
+        HelloEvent {
+            protocol_version: self.protocol_version,
+            min_protocol_version: Some(MIN_PROTOCOL_VERSION),
+            client: ClientKind::Server,
+            client_id: None,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: self.capabilities.clone(),
+        }
+    }
+}
+
+/// Agrees on the highest version both ends speak, peers that can't go down to it or connected
+/// to the wrong endpoint are refused with the reason
+pub fn negotiate(hello: &HelloEvent, expected: ClientKind) -> Result<PeerInfo, String> {
+    if hello.client != expected {
+        return Err(format!("expected a {:?} client, got {:?}", expected, hello.client));
+    }
+
+    let protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+    let min_protocol_version = hello.min_protocol_version.unwrap_or(hello.protocol_version);
+    if protocol_version < min_protocol_version {
+        return Err(format!("client requires protocol version {} or later, server speaks up to {}", min_protocol_version, PROTOCOL_VERSION));
+    }
+
+    Ok(PeerInfo {
+        protocol_version,
+        capabilities: hello.capabilities.iter().filter(|capability| CAPABILITIES.contains(&capability.as_str())).cloned().collect(),
+    })
+}
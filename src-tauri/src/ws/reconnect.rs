@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex as PLMutex;
+
+use crate::ws::DiscordStream;
+use crate::ws::message::StreamKind;
+
+/// How long the links to the streams of a discord client that went away are kept, discord reloading drops the socket
+/// for a few seconds and the plugin reports the same streams once it's back
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    /// Seconds the links are kept pending, 0 unlinks the targets right away
+    pub grace_period: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: 60,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+}
+
+/// The link of a target whose stream went away with its discord client
+#[derive(Clone)]
+pub struct PendingLink {
+    pub stream_id: String,
+    pub user_id: String,
+    pub kind: Option<StreamKind>,
+    /// Auto-link rule that made the link
+    pub rule: Option<String>,
+    pub since: Instant,
+}
+
+impl PendingLink {
+    pub fn new(stream_id: String, stream: &DiscordStream, rule: Option<String>) -> Self {
+        Self {
+            stream_id,
+            user_id: stream.user_id.clone(),
+            kind: stream.kind,
+            rule,
+            since: Instant::now(),
+        }
+    }
+
+    /// The same stream, or a stream of the same user and kind since discord may give it a new id after a reload
+    pub fn matches(&self, stream_id: &str, stream: &DiscordStream) -> bool {
+        self.stream_id == stream_id || (self.user_id == stream.user_id && (self.kind.is_none() || stream.kind.is_none() || self.kind == stream.kind))
+    }
+}
+
+/// Keyed by target id
+pub type PendingLinks = Arc<PLMutex<HashMap<String, PendingLink>>>;
+
+/// The link of a target whose page went away, OBS reloads browser sources and shuts down the hidden ones
+#[derive(Clone)]
+pub struct DetachedLink {
+    pub stream_id: String,
+    /// Auto-link rule that made the link
+    pub rule: Option<String>,
+}
+
+/// Keyed by target id, the target is linked again to the stream when it connects
+pub type DetachedLinks = Arc<PLMutex<HashMap<String, DetachedLink>>>;
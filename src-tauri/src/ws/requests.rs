@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex as PLMutex;
+
+/// Requests left unanswered are forgotten past this many, peers aren't required to answer every one
+const MAX_PENDING: usize = 1024;
+
+/// An outbound request waiting for its `ack` or `error`
+#[derive(Debug)]
+pub struct Pending {
+    pub message: &'static str,
+    pub stream_id: Option<String>,
+    pub session_id: Option<String>,
+}
+
+struct Inner {
+    next_id: u64,
+    pending: BTreeMap<u64, Pending>,
+}
+
+/// Numbers the outbound messages and remembers what they were about, so an error reported by a peer
+/// can be traced back to the stream and the session it concerns
+#[derive(Clone)]
+pub struct Requests {
+    inner: Arc<PLMutex<Inner>>,
+}
+
+impl Requests {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(PLMutex::new(Inner {
+                next_id: 1,
+                pending: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn register(&self, pending: Pending) -> u64 {
+        let mut inner = self.inner.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+
+        if inner.pending.len() == MAX_PENDING {
+            inner.pending.pop_first();
+        }
+        inner.pending.insert(id, pending);
+        id
+    }
+
+    pub fn resolve(&self, id: u64) -> Option<Pending> {
+        self.inner.lock().pending.remove(&id)
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+use tracing::warn;
+
+use crate::ws::DiscordStream;
+use crate::ws::message::StreamKind;
+
+/// Rules linking the new streams to targets without going through the UI
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AutoLinkConfig {
+    /// Evaluated in order, a target is linked by the first rule that has a stream for it
+    pub rules: Vec<AutoLinkRule>,
+    /// Lists of target ids a rule can link by the group name, each target of the group gets a different stream
+    pub target_groups: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+pub struct AutoLinkRule {
+    /// Shown in the UI on the links the rule made
+    pub name: String,
+    #[serde(flatten)]
+    pub matcher: StreamMatch,
+    /// Only camera streams or only screen shares, both when missing
+    #[serde(default)]
+    pub kind: Option<StreamKind>,
+    /// Target id, or the name of a group of `target_groups`
+    pub target: String,
+}
+
+/// Which streams a rule links
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(tag = "match", rename_all = "snake_case")]
+pub enum StreamMatch {
+    /// Any stream, with `kind` every camera or every screen share
+    Any,
+    /// Streams whose nickname matches the regex
+    Nickname { pattern: String },
+    /// Streams of these discord users
+    Users { user_ids: Vec<String> },
+    /// The oldest stream no target is linked to
+    FirstUnassigned,
+}
+
+/// A link decided by a rule
+pub struct AutoLinkDecision {
+    pub target: String,
+    pub stream_id: String,
+    pub rule: String,
+}
+
+struct CompiledRule {
+    rule: AutoLinkRule,
+    /// Set for the nickname rules
+    pattern: Option<Regex>,
+}
+
+/// The rules of the config, with their regexes compiled once. Rules with an invalid regex are left out
+pub struct AutoLink {
+    rules: Vec<CompiledRule>,
+    target_groups: HashMap<String, Vec<String>>,
+}
+
+impl AutoLink {
+    pub fn new(config: &AutoLinkConfig) -> Self {
+        let rules = config.rules.iter()
+            .filter_map(|rule| {
+                let pattern = match &rule.matcher {
+                    StreamMatch::Nickname { pattern } => match Regex::new(pattern) {
+                        Ok(pattern) => Some(pattern),
+                        Err(e) => {
+                            warn!("Ignoring auto-link rule {}, invalid nickname pattern: {}", rule.name, e);
+                            return None;
+                        }
+                    },
+                    _ => None,
+                };
+                Some(CompiledRule {
+                    rule: rule.clone(),
+                    pattern,
+                })
+            })
+            .collect();
+
+        Self {
+            rules,
+            target_groups: config.target_groups.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Picks a stream for each free target a rule applies to. `linked` are the streams targets already watch,
+    /// a stream is only given to one target of a rule so a group spreads over the matching streams
+    pub fn evaluate(&self, streams: &HashMap<String, DiscordStream>, free_targets: &HashSet<String>, linked: &HashSet<String>) -> Vec<AutoLinkDecision> {
+        let mut streams = streams.iter().collect::<Vec<_>>();
+        streams.sort_by(|(a_id, a), (b_id, b)| a.added_at.cmp(&b.added_at).then_with(|| a_id.cmp(b_id)));
+
+        let mut free_targets = free_targets.clone();
+        let mut linked = linked.clone();
+        let mut decisions = Vec::new();
+
+        for CompiledRule { rule, pattern } in &self.rules {
+            let targets = self.target_groups.get(&rule.target).cloned().unwrap_or_else(|| vec![rule.target.clone()]);
+            let mut taken = HashSet::new();
+
+            for target in targets {
+                if !free_targets.contains(&target) {
+                    continue;
+                }
+
+                let stream_id = streams.iter()
+                    .filter(|(stream_id, _)| !taken.contains(*stream_id))
+                    .filter(|(_, stream)| rule.kind.map_or(true, |kind| stream.kind == Some(kind)))
+                    .find(|(stream_id, stream)| match &rule.matcher {
+                        StreamMatch::Any => true,
+                        StreamMatch::Nickname { .. } => pattern.as_ref().map_or(false, |pattern| pattern.is_match(&stream.nickname)),
+                        StreamMatch::Users { user_ids } => user_ids.contains(&stream.user_id),
+                        StreamMatch::FirstUnassigned => !linked.contains(*stream_id),
+                    })
+                    .map(|(stream_id, _)| stream_id.to_string());
+                let Some(stream_id) = stream_id else {
+                    continue;
+                };
+
+                free_targets.remove(&target);
+                taken.insert(stream_id.clone());
+                linked.insert(stream_id.clone());
+                decisions.push(AutoLinkDecision {
+                    target,
+                    stream_id,
+                    rule: rule.name.clone(),
+                });
+            }
+        }
+
+        decisions
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use ts_rs::TS;
+
+/// A layout of the show, keyed by scene name in the config. Targets it doesn't mention keep their link
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SceneConfig {
+    /// What each target shows, keyed by target id
+    pub links: HashMap<String, SceneSource>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SceneSource {
+    /// The live stream of the discord user, the target follows the user's next streams as well
+    User(String),
+    /// A stream by its id, as listed by `/api/streams`
+    Stream(String),
+    Unlinked,
+}
+
+/// Outcome of a scene activation, returned by the command and published on the event stream once every
+/// capture of the scene was asked for
+#[derive(Serialize, Debug, TS, Clone, Default)]
+#[ts(export)]
+pub struct SceneActivatedEvent {
+    pub scene: String,
+    /// Targets linked to another stream, offline ones get it when they connect
+    pub linked: Vec<String>,
+    pub unlinked: Vec<String>,
+    /// Targets that already showed what the scene asks for
+    pub unchanged: Vec<String>,
+    pub failed: Vec<SceneFailure>,
+}
+
+#[derive(Serialize, Debug, TS, Clone)]
+#[ts(export)]
+pub struct SceneFailure {
+    pub target: String,
+    pub reason: String,
+}
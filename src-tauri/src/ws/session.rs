@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+
+use tracing::{info, warn};
+
+use crate::ws::message::MessageType;
+
+/// Candidates kept per direction while waiting for the offer or the answer, past that they are dropped
+const MAX_EARLY_CANDIDATES: usize = 64;
+
+/// Signalling state of one capture of a stream for a target, from the link until the unlink.
+/// Both peers start sending candidates as soon as they set their local description, so they can reach the relay
+/// before the offer (from discord) or the answer (from the target) they belong to. Those are held back here
+/// and flushed right after it, in the order they arrived
+pub struct Session {
+    /// Tags the signals of this capture, discord runs one peer connection per session
+    pub id: String,
+    pub stream_id: String,
+    /// Auto-link rule that made the link, `None` when it was made by hand or by following a user
+    pub rule: Option<String>,
+    offer_forwarded: bool,
+    answer_forwarded: bool,
+    early_to_target: VecDeque<MessageType>,
+    early_to_discord: VecDeque<MessageType>,
+}
+
+impl Session {
+    pub fn new(stream_id: String, rule: Option<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            stream_id,
+            rule,
+            offer_forwarded: false,
+            answer_forwarded: false,
+            early_to_target: VecDeque::new(),
+            early_to_discord: VecDeque::new(),
+        }
+    }
+
+    /// Whether a signal from discord is meant for this session, the ones without a session id come from plugins
+    /// that predate sessions and are matched on the stream only
+    pub fn matches(&self, stream_id: &str, session_id: Option<&str>) -> bool {
+        match session_id {
+            Some(session_id) => self.id == session_id,
+            None => self.stream_id == stream_id,
+        }
+    }
+
+    /// Takes a signal from discord, returns what has to be sent to the target now
+    pub fn from_discord(&mut self, message: MessageType) -> Vec<MessageType> {
+        match message {
+            MessageType::Offer(_) => {
+                // A new offer starts a new negotiation, the target's candidates wait for its new answer
+                self.offer_forwarded = true;
+                self.answer_forwarded = false;
+                std::iter::once(message).chain(self.early_to_target.drain(..)).collect()
+            }
+            MessageType::ICE(_) if !self.offer_forwarded => {
+                queue(&mut self.early_to_target, message, &self.stream_id, "discord");
+                Vec::new()
+            }
+            message => vec![message],
+        }
+    }
+
+    /// Takes a signal from the target, returns what has to be sent to discord now
+    pub fn from_target(&mut self, message: MessageType) -> Vec<MessageType> {
+        match message {
+            MessageType::Answer(_) => {
+                self.answer_forwarded = true;
+                std::iter::once(message).chain(self.early_to_discord.drain(..)).collect()
+            }
+            MessageType::ICE(_) if !self.answer_forwarded => {
+                queue(&mut self.early_to_discord, message, &self.stream_id, "the target");
+                Vec::new()
+            }
+            message => vec![message],
+        }
+    }
+
+    /// Called when the session ends, logs the candidates that never got their offer or answer
+    pub fn close(self) {
+        let dropped = self.early_to_target.len() + self.early_to_discord.len();
+        if dropped > 0 {
+            warn!("Dropped {} early ICE candidates of stream {}, the negotiation never completed", dropped, self.stream_id);
+        }
+    }
+}
+
+fn queue(queue: &mut VecDeque<MessageType>, message: MessageType, stream_id: &str, from: &str) {
+    if queue.len() >= MAX_EARLY_CANDIDATES {
+        warn!("Dropped an ICE candidate from {} for stream {}, too many are waiting", from, stream_id);
+        return;
+    }
+    info!("Queued an early ICE candidate from {} for stream {}", from, stream_id);
+    queue.push_back(message);
+}
@@ -1,7 +1,10 @@
 import { MessageType } from "../bindings/MessageType";
+import {ErrorCode} from "../bindings/ErrorCode";
 import { TypedEventTarget } from 'typescript-event-target';
 import {MessageEventMap} from "../../shared/MappedMessageType";
 import {SharedUtils} from "../../shared/SharedUtils";
+import {ack, error, hello, Signal, SignalEvent} from "../../shared/Protocol";
+import {version} from "../../package.json";
 
 export class WS extends TypedEventTarget<MessageEventMap> {
     private ws: WebSocket;
@@ -9,14 +12,18 @@ export class WS extends TypedEventTarget<MessageEventMap> {
     constructor(url: string) {
         super();
         this.ws = new WebSocket(url);
+        this.ws.addEventListener("open", () => {
+            this.sendEvent(hello("web", version));
+        });
         this.ws.addEventListener("message", (event)=>{
-            let data: MessageType = JSON.parse(event.data)
+            let data: Signal = JSON.parse(event.data)
             // @ts-ignore TODO: fix this
-            this.dispatchTypedEvent(data.type, new CustomEvent(data.type, {detail: data.detail}) as any);
+            this.dispatchTypedEvent(data.type, new SignalEvent(data.type, data.detail, data.requestId) as any);
         });
 
-        this.ws.addEventListener("close", async ()=>{
-            console.error("Websocket connection closed, reloading");
+        // A refused hello closes the connection too, the reload then picks up the page matching the desktop app
+        this.ws.addEventListener("close", async (event)=>{
+            console.error("Websocket connection closed, reloading", event.reason);
 
             await SharedUtils.delay(1000);
 
@@ -32,4 +39,21 @@ export class WS extends TypedEventTarget<MessageEventMap> {
     public sendEvent(event: MessageType) {
         this.ws.send(JSON.stringify(event));
     }
+
+    /**
+     * Acknowledges a message handled successfully, if the desktop app asked for it
+     */
+    public ack(event: SignalEvent<unknown>) {
+        const message = ack(event);
+        if (message) {
+            this.sendEvent(message);
+        }
+    }
+
+    /**
+     * Tells the desktop app why a message couldn't be handled
+     */
+    public fail(event: SignalEvent<unknown>, code: ErrorCode, message: string) {
+        this.sendEvent(error(event, code, message));
+    }
 }
\ No newline at end of file
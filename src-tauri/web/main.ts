@@ -1,9 +1,47 @@
 import {WS} from "./WS";
+import {DisplayOptions} from "../bindings/DisplayOptions";
 
 const video = document.getElementById('video') as HTMLVideoElement;
 
 // @ts-ignore
-const ws = new WS(`ws://127.0.0.1:${window.ws_port}/${window.location.pathname.substring(1)}`);
+const displayOptions: DisplayOptions = window.display_options;
+
+function applyDisplayOptions() {
+    document.body.style.background = displayOptions.background;
+    video.muted = displayOptions.muted;
+    video.style.objectFit = displayOptions.fit;
+
+    const transforms: string[] = [];
+    const crop = displayOptions.crop;
+    if (crop && video.videoWidth && video.videoHeight) {
+        // Scale the video so the crop rectangle fills the page, then move the rectangle in view
+        const scaleX = video.videoWidth / crop.width;
+        const scaleY = video.videoHeight / crop.height;
+        video.style.width = `${scaleX * 100}%`;
+        video.style.height = `${scaleY * 100}%`;
+        video.style.objectFit = "fill";
+        video.style.left = `${-crop.x / crop.width * 100}%`;
+        video.style.top = `${-crop.y / crop.height * 100}%`;
+    }
+    if (displayOptions.mirror) {
+        transforms.push("scaleX(-1)");
+    }
+    video.style.transform = transforms.join(" ");
+}
+
+video.addEventListener("loadedmetadata", applyDisplayOptions);
+video.addEventListener("resize", applyDisplayOptions);
+applyDisplayOptions();
+
+// In single port mode ws_port is null and the WS endpoints are served by this same server under /ws/
+const target = window.location.pathname.substring(1);
+// Pages served over HTTPS connect over WSS, both servers share the same TLS settings
+const wsScheme = window.location.protocol === "https:" ? "wss" : "ws";
+// Targets protected by a token get it in their url as ?token=, it's passed on to the WS handshake
+const token = new URLSearchParams(window.location.search).get("token");
+const wsQuery = token ? `?token=${encodeURIComponent(token)}` : "";
+// @ts-ignore
+const ws = new WS(window.ws_port === null ? `${wsScheme}://${window.location.host}/ws/${target}${wsQuery}` : `${wsScheme}://${window.location.hostname}:${window.ws_port}/${target}${wsQuery}`);
 
 let peerConnection: RTCPeerConnection;
 
@@ -33,34 +71,60 @@ function resetPeerConnection() {
 }
 
 
-ws.addEventListener("ice", (event) => {
+ws.addEventListener("ice", async (event) => {
     console.log("Received ice!");
-    peerConnection.addIceCandidate(new RTCIceCandidate(JSON.parse(event.detail.candidate)));
+    try {
+        await peerConnection.addIceCandidate(new RTCIceCandidate(JSON.parse(event.detail.candidate)));
+        ws.ack(event);
+    } catch (e) {
+        console.error("Failed to add ICE candidate", e);
+        ws.fail(event, "negotiationFailed", `Invalid ICE candidate: ${e}`);
+    }
 });
 
 ws.addEventListener("offer", async (event) => {
     console.log("Received offer!");
-    await peerConnection.setRemoteDescription({
-        type: "offer",
-        sdp: event.detail.sdp
-    });
+    let answer: RTCSessionDescriptionInit;
+    try {
+        await peerConnection.setRemoteDescription({
+            type: "offer",
+            sdp: event.detail.sdp
+        });
 
-    const answer = await peerConnection.createAnswer();
-    //answer.sdp = SharedUtils.forceH264Support(answer.sdp);
-    //answer.sdp = SharedUtils.forceVideoBandwidth(answer.sdp, 90000);
-    await peerConnection.setLocalDescription(answer);
+        answer = await peerConnection.createAnswer();
+        //answer.sdp = SharedUtils.forceH264Support(answer.sdp);
+        //answer.sdp = SharedUtils.forceVideoBandwidth(answer.sdp, 90000);
+        await peerConnection.setLocalDescription(answer);
+    } catch (e) {
+        console.error("Failed to answer the offer", e);
+        ws.fail(event, "negotiationFailed", `Failed to answer the offer: ${e}`);
+        resetPeerConnection();
+        return;
+    }
 
     ws.sendEvent({
         type: "answer", detail: {
             sdp: answer.sdp
         }
     })
+    ws.ack(event);
+});
+
+// The capture of the linked stream failed on discord or a signal couldn't be delivered, start over clean
+// so the next offer isn't mixed with the failed negotiation
+ws.addEventListener("error", (event) => {
+    console.error(`Signalling error (${event.detail.code}): ${event.detail.message}`);
+    if (event.detail.code === "streamNotFound" || event.detail.code === "negotiationFailed") {
+        resetPeerConnection();
+        video.srcObject = null;
+    }
 });
 
-ws.addEventListener("unlink", async () => {
+ws.addEventListener("unlink", async (event) => {
     resetPeerConnection();
 
     video.srcObject = null;
+    ws.ack(event);
 });
 
 resetPeerConnection();
\ No newline at end of file
@@ -2,7 +2,6 @@ import {defineConfig, UserConfigExport} from "vite";
 import vue from "@vitejs/plugin-vue";
 import vuetify, {transformAssetUrls} from "vite-plugin-vuetify";
 import {fileURLToPath} from "url";
-import { viteSingleFile } from "vite-plugin-singlefile"
 import merge from "deepmerge";
 
 // https://vitejs.dev/config/
@@ -43,16 +42,15 @@ export default defineConfig((mode) => {
                     '@': fileURLToPath(new URL('./src-tauri/web', import.meta.url))
                 }
             },
-            plugins: [viteSingleFile({
-                removeViteModuleLoader: false,
-            })],
-            root: "src-tauri/",
+            // The whole bundle is embedded by the desktop app and served by its web server, assets land in dist/web/assets
+            root: "src-tauri/web/",
+            base: "/",
             build: {
-                outDir: "dist",
+                outDir: "../dist/web",
                 sourcemap: false,
                 rollupOptions: {
                     input: {
-                        app: "src-tauri/web/index.html"
+                        app: fileURLToPath(new URL('./src-tauri/web/index.html', import.meta.url))
                     }
                 }
             }